@@ -14,6 +14,7 @@ uint8_t flag[8192];
 #define RIP_MAX_ENTRY 25
 #define TABLE_MAX_ITEM 1000
 #define PACKET_MAX_LENGTH 2048
+#define RIP_TOS 0xc0 // precedence: internetwork control
 
 uint32_t addrs[4]; // should know this
 uint32_t N_IFACE_ON_BOARD; // should know this
@@ -97,15 +98,16 @@ uint32_t read_u32(const uint8_t *ptr) {
 
 extern "C" {
     /**
-     * @brief 接收一个 IP 报文
+     * @brief 接收一个 UDP 报文，IP 与 UDP 头部已由固件检查
      *
-     * @param packet 实际接收的报文
-     * @param length 实际接收的报文长度
+     * @param payload UDP 载荷
+     * @param length 载荷长度
+     * @param src 来源 IP 地址
      * @param src_mac IPv4 报文下层的来源 MAC 地址
      * @param if_index 实际接收到的报文来源的接口号
      * @return int 0 表示成功，非 0 为失败
      */
-    uint64_t Meow_ReceiveIPPacket(uint8_t *packet, size_t length, macaddr_t src_mac, int if_index, RoutingTableEntry *tbl, uint64_t tblsize);
+    uint64_t Meow_ReceiveUDPPacket(const uint8_t *payload, size_t length, uint32_t src, macaddr_t src_mac, int if_index, RoutingTableEntry *tbl, uint64_t tblsize);
 
     /**
      * @brief 发送一个 IP 报文
@@ -118,6 +120,22 @@ extern "C" {
      */
     uint64_t Meow_SendIPPacket(uint8_t *buffer, size_t length, uint8_t if_index, macaddr_t dst_mac);
 
+    /**
+     * @brief 发送一个 UDP 报文，IP 与 UDP 头部由固件填写
+     *
+     * @param payload UDP 载荷
+     * @param length 载荷长度
+     * @param sport 源端口
+     * @param dport 目的端口
+     * @param dst 目的 IP 地址
+     * @param ttl IP 报文的 TTL
+     * @param tos IP 报文的服务类型
+     * @param if_index 实际发送报文的接口号
+     * @param dst_mac IPv4 报文下层的目的 MAC 地址
+     * @return int 0 表示成功，非 0 为失败
+     */
+    uint64_t Meow_SendUDPPacket(const uint8_t *payload, size_t length, uint16_t sport, uint16_t dport, uint32_t dst, uint8_t ttl, uint8_t tos, uint8_t if_index, macaddr_t dst_mac);
+
     /**
      * @brief 定时器过期时触发
      *
@@ -137,6 +155,15 @@ extern "C" {
 
     bool Meow_Update(bool insert, RoutingTableEntry *entry);
 
+    /**
+     * @brief 计算 Internet 校验和
+     *
+     * @param data 校验和字段已清零的数据
+     * @param len 数据长度
+     * @return uint16_t 主机字节序的校验和
+     */
+    uint16_t Meow_Checksum(const uint8_t *data, size_t len);
+
     inline uint32_t ip_serialize(uint8_t ip[4]) {
         return ip[0] | (ip[1] << 8) | (ip[2] << 16) | (ip[3] << 24);
     } 
@@ -145,39 +172,6 @@ extern "C" {
         return ((a & 0xff00) >> 8) + ((a & 0xff) << 8);
     }
 
-    inline uint16_t HeaderChecksum(uint16_t *packet, int len) {
-        uint32_t checksum = 0;
-        for (int i = 0; i < len; i++)
-            checksum += packet[i];
-        while (checksum >= (1 << 16))
-            checksum = checksum % (1 << 16) + checksum / (1 << 16);
-        return ~(checksum % (1 << 16));
-    }
-
-    uint16_t identification;
-
-    inline void IPHeaderAssemble(uint8_t *packet, uint32_t &len, uint32_t src, uint32_t dst) {
-        packet[0] = 0x45; // Version & Header length
-        packet[1] = 0xc0; // ToS
-        *(uint16_t *)(packet+2) = change_endian_16(len += 20);
-        *(uint16_t *)(packet+4) = (identification += len); // ID
-        // *(uint16_t *)(packet+4) = 0x22e7; // ID
-        *(uint16_t *)(packet+6) = 0; // Flagment
-        packet[8] = 1; // TTL
-        packet[9] = 0x11; // Protocol: UDP:0x11 TCP:0x06 ICMP:0x01
-        *(uint16_t *)(packet+10) = 0; // checksum
-        write_u32(packet+12, src); // src ip
-        write_u32(packet+16, dst); // dst ip
-        *(uint16_t *)(packet+10) = HeaderChecksum((uint16_t *)packet, 20 / 2);
-    }
-
-    inline void UDPHeaderAssemble(uint8_t *packet, uint32_t &len, uint16_t sport, uint16_t dport) { // 520, 520
-        *(uint16_t *)(packet+0) = change_endian_16(sport); // src port
-        *(uint16_t *)(packet+2) = change_endian_16(dport); // dst port
-        *(uint16_t *)(packet+4) = change_endian_16(len += 8);
-        *(uint16_t *)(packet+6) = 0;
-    }
-
     inline void RIPAssemble(uint8_t *packet, uint32_t &len, const RipPacket *rip) {
         packet[0] = rip->command; // command: request:1 response:2
         packet[1] = 0x02; // version
@@ -255,26 +249,25 @@ extern "C" {
         return a;
     }
 
-    inline bool disassemble(const uint8_t *packet, uint32_t len, RipPacket *output, uint16_t &checksum, uint32_t &ipsrc) {
-        checksum = (uint16_t)read_u32(packet + 26);
-        ipsrc = read_u32(packet + 12);
-        if ((len - 32) % 20 != 0) return false;
-        output->numEntries = (len - 32) / 20;
+    inline bool disassemble(const uint8_t *rip, uint32_t len, RipPacket *output) {
+        if (len < 4) return false;
+        if ((len - 4) % 20 != 0) return false;
+        output->numEntries = (len - 4) / 20;
         if (output->numEntries > RIP_MAX_ENTRY) return false;
-        if (packet[28] != 1 && packet[28] != 2) return false;
-        if (packet[29] != 2) return false;
-        for (int i = 0; i < (len - 32) / 20; i++) {
-            output->entries[i].addr = read_u32(packet + 32 + i * 20 + 4);
-            output->entries[i].mask = read_u32(packet + 32 + i * 20 + 8);
+        if (rip[0] != 1 && rip[0] != 2) return false;
+        if (rip[1] != 2) return false;
+        for (int i = 0; i < output->numEntries; i++) {
+            output->entries[i].addr = read_u32(rip + 4 + i * 20 + 4);
+            output->entries[i].mask = read_u32(rip + 4 + i * 20 + 8);
             if (count_bit(output->entries[i].mask + 1) > 1) return false;
-            output->entries[i].nexthop = read_u32(packet + 32 + i * 20 + 12);
-            output->entries[i].metric = read_u32(packet + 32 + i * 20 + 16);
+            output->entries[i].nexthop = read_u32(rip + 4 + i * 20 + 12);
+            output->entries[i].metric = read_u32(rip + 4 + i * 20 + 16);
             if (output->entries[i].metric & 0xffffff) return false;
             if ((output->entries[i].metric >> 24) < 0x01 ||
                 (output->entries[i].metric >> 24) > 0x10) return false;
             output->entries[i].metric >>= 24;
         }
-        output->command = packet[28];
+        output->command = rip[0];
         return true;
     }
 
@@ -298,10 +291,8 @@ extern "C" {
 
         RipPacket p;
         require(&p);
-        RIPAssemble(output + 20 + 8, out_len = 0, &p);
-        UDPHeaderAssemble(output + 20, out_len, 520, 520);
-        IPHeaderAssemble(output, out_len, addrs[N_IFACE_ON_BOARD], multicasting_ip);
-        Meow_SendIPPacket(output, out_len, N_IFACE_ON_BOARD, multicasting_mac);
+        RIPAssemble(output, out_len = 0, &p);
+        Meow_SendUDPPacket(output, out_len, 520, 520, multicasting_ip, 1, RIP_TOS, N_IFACE_ON_BOARD, multicasting_mac);
 
         N_IFACE_ON_BOARD++;
     }
@@ -315,7 +306,6 @@ extern "C" {
         multicasting_mac[3] = 0x00;
         multicasting_mac[4] = 0x00;
         multicasting_mac[5] = 0x09;
-        identification = 0x4c80;
         N_IFACE_ON_BOARD = 0;
         for (uint i = 0; i < 8192; i++) flag[i] = (uint8_t)0;
         return 0;
@@ -332,10 +322,8 @@ extern "C" {
                 uint32_t res = 0;
                 while (res < tblsize) {
                     broadtable(&p, i, res, tbl, tblsize);
-                    RIPAssemble(output + 20 + 8, out_len = 0, &p);
-                    UDPHeaderAssemble(output + 20, out_len, 520, 520);
-                    IPHeaderAssemble(output, out_len, addrs[i], multicasting_ip);
-                    Meow_SendIPPacket(output, out_len, i, multicasting_mac);
+                    RIPAssemble(output, out_len = 0, &p);
+                    Meow_SendUDPPacket(output, out_len, 520, 520, multicasting_ip, 1, RIP_TOS, i, multicasting_mac);
                 }
             }
             now = usec;
@@ -343,28 +331,24 @@ extern "C" {
         return 0;
     }
 
-    uint64_t Meow_ReceiveIPPacket(uint8_t *packet, size_t length, macaddr_t src_mac, int if_index, RoutingTableEntry *tbl, uint64_t tblsize) { // legal
-        // FIXME: IP checksum
-        in_addr_t src_addr = read_u32(packet + 12);
-        in_addr_t dst_addr = read_u32(packet + 16);
+    uint64_t Meow_ReceiveUDPPacket(const uint8_t *payload, size_t length, uint32_t src, macaddr_t src_mac, int if_index, RoutingTableEntry *tbl, uint64_t tblsize) { // legal
+        // The IP and UDP headers, checksums included, are validated by the firmware before we are called
 
         RipPacket rip;
-        uint16_t checksum;
-        uint32_t ipsrc;
-        if (disassemble((uint8_t *)packet, length, &rip, checksum, ipsrc)) {
+        if (disassemble(payload, length, &rip)) {
             if (rip.command == 1) { // receive a request packet
                 RipPacket p;
                 uint32_t res = 0;
                 while (res < tblsize) {
 
                     broadtable(&p, if_index, res, tbl, tblsize);
-                    RIPAssemble(output + 20 + 8, out_len = 0, &p);
-                    UDPHeaderAssemble(output + 20, out_len, 520, 520);
-                    IPHeaderAssemble(output, out_len, addrs[if_index], src_addr);
-                    Meow_SendIPPacket(output, out_len, if_index, src_mac);
+                    RIPAssemble(output, out_len = 0, &p);
+                    Meow_SendUDPPacket(output, out_len, 520, 520, src, 1, RIP_TOS, if_index, src_mac);
                 }
                 // TODO: set a flag, wait for response
             } else {  // receive a response packet
+                // Repeated responses are recognized by their contents and sender
+                uint16_t checksum = Meow_Checksum(payload, length) ^ (uint16_t)src ^ (uint16_t)(src >> 16);
                 if ((flag[checksum >> 3] & (1 << (checksum & 7))) && ((checksum ^ (uint16_t)now) & 15) < 14) return 0;
                 flag[checksum >> 3] |= (1 << (checksum & 7));
                 RipPacket p;
@@ -381,7 +365,7 @@ extern "C" {
 
                     if(defaultRoute)
                       for(int i = 0; i<4; ++i)
-                        record.nexthop[i] = ipsrc >> (i * 8);
+                        record.nexthop[i] = src >> (i * 8);
 
                     if (Meow_Update(true, &record)) {
                         p.entries[p.numEntries++] = {
@@ -393,10 +377,8 @@ extern "C" {
                     }
                 }
                 if (p.numEntries > 0) {
-                    RIPAssemble(output + 20 + 8, out_len = 0, &p);
-                    UDPHeaderAssemble(output + 20, out_len, 520, 520);
-                    IPHeaderAssemble(output, out_len, addrs[if_index], src_addr);
-                    Meow_SendIPPacket(output, out_len, if_index, src_mac);
+                    RIPAssemble(output, out_len = 0, &p);
+                    Meow_SendUDPPacket(output, out_len, 520, 520, src, 1, RIP_TOS, if_index, src_mac);
                 }
             }
         } else {
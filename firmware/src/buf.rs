@@ -1,8 +1,6 @@
-use crate::data::arp::ARP;
+use crate::packet::{Bytes, EthernetFrame, Ipv4Packet, ETH_HEADER_LEN};
 use crate::util::*;
 
-const IP_OUTGOING_TTL: u8 = 64;
-
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq)]
 pub enum BufState {
@@ -43,8 +41,8 @@ pub struct BufHandle {
 }
 
 pub enum ParsedBufHandle {
-    ARP(*mut ARP),
-    IPv4(IPv4Handle, *mut u8),
+    ARP(Bytes),
+    IPv4(Ipv4Packet),
     Unknown,
 }
 
@@ -53,6 +51,22 @@ impl BufHandle {
         unsafe { core::mem::transmute(BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE) }
     }
 
+    /// The frame in the cell, up to the length and status trailer
+    pub fn frame(&self) -> EthernetFrame {
+        EthernetFrame::new(Bytes::new(self.raw(), (BUF_CELL_SIZE - 4) as usize))
+    }
+
+    /// The payload of a received frame, cut to the cell if its length is bogus
+    pub fn payload(&self) -> Bytes {
+        let room = self.room();
+        room.get(0, self.payload_len() as usize).unwrap_or(room)
+    }
+
+    /// Room for the payload of a frame to be sent
+    pub fn room(&self) -> Bytes {
+        self.frame().payload()
+    }
+
     pub fn probe(&self) -> BufState {
         let status_addr = BUF_BASE + (self.ptr as u64 + 1) * BUF_CELL_SIZE - 1;
         unsafe {
@@ -75,28 +89,32 @@ impl BufHandle {
     }
 
     pub fn dest(&self) -> [u8;6] {
-        unsafe { core::ptr::read_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE) as *const [u8;6]) }
+        self.frame().dest()
     }
 
     pub fn src(&self) -> [u8;6] {
-        unsafe { core::ptr::read_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 6) as *const [u8;6]) }
+        self.frame().src()
+    }
+
+    /// If the frame was sent to the broadcast or a multicast MAC
+    pub fn link_broadcast(&self) -> bool {
+        self.dest()[0] & 1 != 0
     }
 
     pub fn port(&self) -> u8 {
-        unsafe { core::ptr::read_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 15) as *const u8) }
+        self.frame().port()
     }
 
     pub fn write_dest(&self, mac: [u8;6]) {
-        unsafe { core::ptr::write_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE) as *mut [u8;6], mac); }
+        self.frame().set_dest(mac);
     }
 
     pub fn write_src(&self, mac: [u8;6]) {
-        unsafe { core::ptr::write_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 6) as *mut [u8;6], mac); }
+        self.frame().set_src(mac);
     }
 
     pub fn write_port(&self, port: u8) {
-        unsafe { core::ptr::write_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 12) as *mut u32, 0x81); }
-        unsafe { core::ptr::write_volatile((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 15) as *mut u8, port); }
+        self.frame().set_port(port);
     }
 
     fn write_state(&mut self, state: BufState) {
@@ -119,40 +137,20 @@ impl BufHandle {
     }
 
     pub fn parse(&self) -> ParsedBufHandle {
-        let et = self.eth_type();
-        // hprint("ETHTYPE:");
-        // hprint_hex(unsafe {&core::intrinsics::transmute::<_, [u8; 2]>(et)});
-        // hprint("\n\r");
-
-        if et == EthType::ARP { ParsedBufHandle::ARP((BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 18) as *mut ARP) }
-        else if et == EthType::IPv4 {
-            ParsedBufHandle::IPv4(
-                IPv4Handle {
-                    ptr: (BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 18) as *mut u8,
-                },
-                (BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 18 + 20) as *mut u8,
-            )
-        } else {
-            ParsedBufHandle::Unknown
+        match self.eth_type() {
+            Some(EthType::ARP) => ParsedBufHandle::ARP(self.payload()),
+            // Expects the datagram to be checked by Ipv4Packet::new_checked
+            Some(EthType::IPv4) => ParsedBufHandle::IPv4(Ipv4Packet::new_unchecked(self.payload())),
+            _ => ParsedBufHandle::Unknown,
         }
     }
 
-    pub fn data(&mut self) -> *mut u8 {
-        (BUF_BASE + self.ptr as u64 * BUF_CELL_SIZE + 18) as *mut u8
-    }
-
-    pub fn eth_type(&self) -> EthType {
-        let eth_type_addr = BUF_BASE + (self.ptr as u64) * BUF_CELL_SIZE + 16;
-        unsafe {
-            core::ptr::read_volatile(eth_type_addr as *const EthType)
-        }
+    pub fn eth_type(&self) -> Option<EthType> {
+        self.frame().eth_type()
     }
 
     pub fn write_eth_type(&self, t: EthType) {
-        let eth_type_addr = BUF_BASE + (self.ptr as u64) * BUF_CELL_SIZE + 16;
-        unsafe {
-            core::ptr::write_volatile(eth_type_addr as *mut EthType, t);
-        }
+        self.frame().set_eth_type(t);
     }
 
     pub fn dump(&self) {
@@ -179,14 +177,14 @@ impl BufHandle {
     pub fn write_payload_len(&mut self, len: u16) {
         let len_addr = BUF_BASE + (self.ptr as u64 + 1) * BUF_CELL_SIZE - 4;
         let len = unsafe {
-            core::ptr::write_volatile(len_addr as *mut u16, len + 18)
+            core::ptr::write_volatile(len_addr as *mut u16, len + ETH_HEADER_LEN as u16)
         };
     }
 
-    pub fn payload_len(&mut self) -> u16 {
+    pub fn payload_len(&self) -> u16 {
         let len_addr = BUF_BASE + (self.ptr as u64 + 1) * BUF_CELL_SIZE - 4;
         unsafe {
-            core::ptr::read_volatile(len_addr as *const u16) - 18
+            core::ptr::read_volatile(len_addr as *const u16).saturating_sub(ETH_HEADER_LEN as u16)
         }
     }
 }
@@ -212,136 +210,278 @@ pub fn snd_buf() -> BufHandle {
     }
 }
 
-pub struct IPv4Handle {
-    ptr: *mut u8,
-}
-
-#[repr(u8)]
-#[derive(Clone, Copy, PartialEq)]
-pub enum IPProto {
-    ICMP = 0x01,
-    IGMP = 0x02,
-    TCP = 0x06,
-    UDP = 0x11,
-}
+/// ICMP types, codes and policies of the router
+pub mod icmp {
+    use crate::builder::PacketBuilder;
+    use crate::packet::{Bytes, Ipv4Packet, IPProto};
+    use crate::util::*;
 
-impl IPv4Handle {
-    pub fn allocate(buf: *mut u8) -> (IPv4Handle, *mut u8) {
-        unsafe {
-            (IPv4Handle { ptr: buf }, buf.offset(20))
+    #[repr(u8)]
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum ICMPType {
+        EchoReply = 0,
+        Unreachable = 3,
+        Redirect = 5,
+        EchoRequest = 8,
+        RouterAd = 9,
+        RouterSol = 10,
+        TimeExceeded = 11,
+        BadIPHeader = 12,
+        Timestamp = 13,
+        TimestampReply = 14,
+        InfoRequest = 15,
+        InfoReply = 16,
+    }
+
+    pub const ICMP_UNREACH_NET: u8 = 0;
+    pub const ICMP_UNREACH_HOST: u8 = 1;
+    pub const ICMP_UNREACH_NEEDFRAG: u8 = 4;
+    pub const ICMP_TIME_EXCEEDED_TTL: u8 = 0;
+    pub const ICMP_TIME_EXCEEDED_REASM: u8 = 1;
+    pub const ICMP_REDIRECT_HOST: u8 = 1;
+
+    /// Bytes of the offending datagram quoted after its IP header in error messages
+    pub const ERROR_QUOTE_LEN: usize = 8;
+
+    /// Length of timestamp requests and replies: header, originate, receive and transmit
+    pub const TIMESTAMP_LEN: usize = 20;
+
+    /// All-systems multicast group router advertisements are sent to
+    pub const ALL_SYSTEMS: [u8; 4] = [224, 0, 0, 1];
+
+    /// Adds a router advertisement for the single address addr to builder (RFC 1256)
+    pub fn router_ad(builder: &mut PacketBuilder, addr: [u8; 4], lifetime: u16, preference: i32) {
+        // One address, two 32-bit words per entry
+        builder
+            .icmp(ICMPType::RouterAd as u8, 0, [1, 2, (lifetime >> 8) as u8, lifetime as u8])
+            .payload(&addr)
+            .payload(&preference.to_be_bytes());
+    }
+
+    /**
+     * Current time in the format of ICMP timestamps: ms since midnight UT.
+     * Before the wall clock is aligned, the time since boot is sent with the high bit set,
+     * which RFC 792 reserves for non-standard values.
+     */
+    pub fn timestamp() -> u32 {
+        const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+        match crate::util::wall_time() {
+            Some(unix) => ((unix / 1000) % MS_PER_DAY) as u32,
+            None => (crate::util::cur_time() / 1000) as u32 | 0x8000_0000,
         }
     }
 
-    pub fn proto(&self) -> IPProto {
-        unsafe { core::ptr::read_volatile(self.ptr.offset(9) as *const IPProto) }
-    }
-
-    pub fn src(&self) -> [u8; 4] {
-        let mut ret = [0; 4];
-
-        for i in 0..4usize {
-            unsafe { ret[i] = core::ptr::read_volatile(self.ptr.offset(12 + i as isize)); }
+    /// Why an ICMP error was not sent
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Suppress {
+        RateLimited,
+        /// The offending datagram is an ICMP error itself
+        AboutError,
+        /// The offending datagram was sent to a broadcast or multicast address, or in such a frame
+        Broadcast,
+        /// Only the first fragment of a datagram is reported
+        Fragment,
+        /// The source of the offending datagram does not identify a single host
+        BadSource,
+    }
+
+    /**
+     * Checks the RFC 1812 4.3.2.7 rules for sending an ICMP error about the
+     * datagram at orig. Directed broadcasts depend on the subnets, and are recognized
+     * by is_broadcast. link_broadcast tells if the datagram came in a link-layer
+     * broadcast or multicast frame.
+     */
+    pub fn error_rules<F: Fn(&[u8; 4]) -> bool>(orig: &Bytes, is_broadcast: F, link_broadcast: bool) -> Result<(), Suppress> {
+        let ip = Ipv4Packet::new_unchecked(*orig);
+        let (src, dest) = (ip.src(), ip.dest());
+
+        if src == [0, 0, 0, 0] || src == [255, 255, 255, 255] || src[0] == 127 || src[0] >= 224 || is_broadcast(&src) {
+            return Err(Suppress::BadSource);
         }
 
-        ret
-    }
+        if link_broadcast || dest == [255, 255, 255, 255] || dest[0] & 0xF0 == 224 || is_broadcast(&dest) {
+            return Err(Suppress::Broadcast);
+        }
 
-    pub fn dest(&self) -> [u8; 4] {
-        let mut ret = [0; 4];
+        if ip.frag_offset() != 0 {
+            return Err(Suppress::Fragment);
+        }
 
-        for i in 0..4usize {
-            unsafe { ret[i] = core::ptr::read_volatile(self.ptr.offset(16 + i as isize)); }
+        let ihl = ip.header_len() as usize;
+        if ip.proto() == IPProto::ICMP as u8 && orig.get_u8(ihl).map_or(false, is_error) {
+            return Err(Suppress::AboutError);
         }
 
-        ret
+        Ok(())
     }
 
-    pub fn fill_chksum(&mut self) {
-        // Assume aligned
-        let mut sum = 0u32;
-        for i in 0..10 {
-            if i == 5 { continue; }
-
-            unsafe {
-                let readout = u16::from_be(*((self.ptr as *const u16).offset(i)));
-                sum += readout as u32;
-            }
-        }
+    /// If an ICMP message of type r#type reports an error, as opposed to being a query
+    pub fn is_error(r#type: u8) -> bool {
+        r#type == ICMPType::Unreachable as u8
+            || r#type == 4 // Source quench
+            || r#type == ICMPType::Redirect as u8
+            || r#type == ICMPType::TimeExceeded as u8
+            || r#type == ICMPType::BadIPHeader as u8
+    }
 
-        while (sum >> 16) > 0 {
-            sum = (sum & 0xFFFF) + (sum >> 16)
-        }
+    /// Error types with their own limit: burst and refill interval in us
+    const LIMITS: [(ICMPType, u32, u64); 4] = [
+        (ICMPType::Unreachable, 10, 100_000),
+        (ICMPType::TimeExceeded, 10, 100_000),
+        (ICMPType::BadIPHeader, 10, 100_000),
+        (ICMPType::Redirect, 4, 500_000),
+    ];
 
-        unsafe {
-            *(self.ptr as *mut u16).offset(5) = !u16::to_be(sum as u16);
-        }
+    #[derive(Default, Clone, Copy)]
+    pub struct ErrorCounters {
+        pub sent: u64,
+        pub rate_limited: u64,
+        pub about_error: u64,
+        pub broadcast: u64,
+        pub fragment: u64,
+        pub bad_source: u64,
     }
 
-    pub fn payload_len(&self) -> u16 {
-        u16::from_be(unsafe { core::ptr::read_volatile(self.ptr.offset(2) as *const u16) }) - 20
+    /// Decides which ICMP errors are sent, with a token bucket per type and interface
+    pub struct ErrorLimiter<const PORTS: usize> {
+        buckets: [[TokenBucket; LIMITS.len()]; PORTS],
+        counters: [ErrorCounters; PORTS],
     }
 
-    pub fn outgoing(&mut self, proto: IPProto, payload_len: u16, src: [u8; 4], dest: [u8; 4]) {
-        unsafe {
-            // Assumes zero-initialized
-            core::ptr::write_volatile(self.ptr, (4 << 4) | 5);
-            core::ptr::write_volatile(self.ptr.offset(2) as *mut u16, u16::to_be(payload_len + 20));
-
-            core::ptr::write_volatile(self.ptr.offset(8), IP_OUTGOING_TTL);
-            core::ptr::write_volatile(self.ptr.offset(9), proto as u8);
-            for i in 0..4usize {
-                core::ptr::write_volatile(self.ptr.offset(12 + i as isize), src[i]);
+    impl<const PORTS: usize> ErrorLimiter<{PORTS}> {
+        pub fn new() -> Self {
+            let mut buckets = [TokenBucket::new(0, 1); LIMITS.len()];
+            for (bucket, (_, burst, interval)) in buckets.iter_mut().zip(LIMITS.iter()) {
+                *bucket = TokenBucket::new(*burst, *interval);
             }
 
-            for i in 0..4usize {
-                core::ptr::write_volatile(self.ptr.offset(16 + i as isize), dest[i]);
+            Self {
+                buckets: [buckets; PORTS],
+                counters: [ErrorCounters::default(); PORTS],
             }
         }
 
-        self.fill_chksum();
-    }
-}
-
-pub mod icmp {
-    #[repr(u8)]
-    #[derive(Clone, Copy, PartialEq)]
-    pub enum ICMPType {
-        EchoReply = 0,
-        Unreachable = 3,
-        Redirect = 5,
-        EchoRequest = 8,
-        RouterAd = 9,
-        RouterSol = 10,
-        TimeExceeded = 11,
-        BadIPHeader = 12,
-        Timestamp = 13,
-        TimestampReply = 14,
-    }
+        /**
+         * If an error of type r#type about the datagram in orig may be sent through port.
+         * Suppressed errors are counted against that port.
+         */
+        pub fn check<F: Fn(&[u8; 4]) -> bool>(
+            &mut self,
+            port: u8,
+            r#type: ICMPType,
+            orig: &Bytes,
+            is_broadcast: F,
+            link_broadcast: bool,
+            now: u64,
+        ) -> bool {
+            let result = error_rules(orig, is_broadcast, link_broadcast).and_then(|_| {
+                match LIMITS.iter().position(|(t, _, _)| *t == r#type) {
+                    Some(idx) if !self.buckets[port as usize][idx].take(now) => Err(Suppress::RateLimited),
+                    _ => Ok(()),
+                }
+            });
+
+            let cnt = &mut self.counters[port as usize];
+            match result {
+                Ok(()) => cnt.sent += 1,
+                Err(Suppress::RateLimited) => cnt.rate_limited += 1,
+                Err(Suppress::AboutError) => cnt.about_error += 1,
+                Err(Suppress::Broadcast) => cnt.broadcast += 1,
+                Err(Suppress::Fragment) => cnt.fragment += 1,
+                Err(Suppress::BadSource) => cnt.bad_source += 1,
+            }
 
-    #[repr(C)]
-    pub struct ICMPHeader<const U16_BODY: usize> {
-        pub r#type: ICMPType,
-        pub code: u8,
-        pub chksum: u16,
-        pub rest: [u16; 2],
-        pub body: [u16; U16_BODY],
-    }
+            result.is_ok()
+        }
 
-    impl<const U16_BODY: usize> ICMPHeader<{U16_BODY}> {
-        pub fn fill_chksum(&mut self, totlen: u16) {
-            let mut sum: u32 =
-                ((self.r#type as u32) << 8) + self.code as u32 +
-                u16::from_be(self.rest[0]) as u32 + u16::from_be(self.rest[1]) as u32;
+        pub fn counters(&self, port: u8) -> &ErrorCounters {
+            &self.counters[port as usize]
+        }
 
-            for idx in 0..(totlen/2 - 4) {
-                sum += u16::from_be(self.body[idx as usize]) as u32;
+        pub fn hprint_counters(&self) {
+            for (port, cnt) in self.counters.iter().enumerate() {
+                hprint("ICMP errors @ ");
+                hprint_dec(port as u64);
+                hprint(": sent ");
+                hprint_dec(cnt.sent);
+                hprint(", limited ");
+                hprint_dec(cnt.rate_limited);
+                hprint(", about errors ");
+                hprint_dec(cnt.about_error);
+                hprint(", broadcast ");
+                hprint_dec(cnt.broadcast);
+                hprint(", fragment ");
+                hprint_dec(cnt.fragment);
+                hprint(", bad source ");
+                hprint_dec(cnt.bad_source);
+                hprint("\n\r");
             }
+        }
+    }
 
-            while (sum >> 16) > 0 {
-                sum = (sum & 0xFFFF) + (sum >> 16);
-            }
+    /**
+     * The part of the datagram in orig quoted by ICMP errors about it:
+     * its IPv4 header plus the first 8 bytes of its payload (RFC 792),
+     * or less should the datagram be cut short.
+     */
+    pub fn error_quote(orig: &Bytes) -> Bytes {
+        let ihl = Ipv4Packet::new_unchecked(*orig).header_len() as usize;
+        orig.slice(0, core::cmp::min(ihl + ERROR_QUOTE_LEN, orig.len()))
+    }
+}
 
-            self.chksum = !u16::to_be(sum as u16);
-        }
+#[cfg(test)]
+mod tests {
+    use crate::packet::{Bytes, Ipv4Packet, IPProto};
+    use super::icmp::*;
+
+    fn check(src: [u8; 4], dest: [u8; 4], link_broadcast: bool) -> Result<(), Suppress> {
+        let mut buf = [0u8; 28];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+        Ipv4Packet::new_unchecked(bytes).outgoing_with_options(IPProto::UDP, 8, src, dest, 0);
+
+        let subnet_broadcast = |ip: &[u8; 4]| *ip == [10, 0, 0, 255];
+        error_rules(&bytes, subnet_broadcast, link_broadcast)
+    }
+
+    /// Checks a unicast datagram of proto whose payload starts with first, at fragment offset
+    fn check_payload(proto: IPProto, first: u8, offset: usize) -> Result<(), Suppress> {
+        let mut buf = [0u8; 28];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+        let mut ip = Ipv4Packet::new_unchecked(bytes);
+        ip.outgoing_with_options(proto, 8, [10, 0, 0, 2], [192, 168, 0, 1], 0);
+        ip.set_fragment(8, offset, false);
+        bytes.set_u8(20, first);
+
+        error_rules(&bytes, |_| false, false)
+    }
+
+    #[test]
+    fn test_error_rules() {
+        assert_eq!(check([10, 0, 0, 2], [192, 168, 0, 1], false), Ok(()));
+
+        assert_eq!(check([10, 0, 0, 2], [10, 0, 0, 255], false), Err(Suppress::Broadcast));
+        assert_eq!(check([10, 0, 0, 2], [255, 255, 255, 255], false), Err(Suppress::Broadcast));
+        assert_eq!(check([10, 0, 0, 2], [224, 0, 0, 9], false), Err(Suppress::Broadcast));
+        assert_eq!(check([10, 0, 0, 2], [192, 168, 0, 1], true), Err(Suppress::Broadcast));
+
+        assert_eq!(check([10, 0, 0, 255], [192, 168, 0, 1], false), Err(Suppress::BadSource));
+        assert_eq!(check([0, 0, 0, 0], [192, 168, 0, 1], false), Err(Suppress::BadSource));
+        assert_eq!(check([127, 0, 0, 1], [192, 168, 0, 1], false), Err(Suppress::BadSource));
+
+        // Only first fragments are reported
+        assert_eq!(check_payload(IPProto::UDP, 0, 0), Ok(()));
+        assert_eq!(check_payload(IPProto::UDP, 0, 8), Err(Suppress::Fragment));
+        assert_eq!(check_payload(IPProto::ICMP, ICMPType::EchoRequest as u8, 1480), Err(Suppress::Fragment));
+
+        // ICMP errors are never reported, ICMP queries are
+        assert_eq!(check_payload(IPProto::ICMP, ICMPType::Unreachable as u8, 0), Err(Suppress::AboutError));
+        assert_eq!(check_payload(IPProto::ICMP, ICMPType::TimeExceeded as u8, 0), Err(Suppress::AboutError));
+        assert_eq!(check_payload(IPProto::ICMP, ICMPType::Redirect as u8, 0), Err(Suppress::AboutError));
+        assert_eq!(check_payload(IPProto::ICMP, 4, 0), Err(Suppress::AboutError));
+        assert_eq!(check_payload(IPProto::ICMP, ICMPType::EchoRequest as u8, 0), Ok(()));
+        assert_eq!(check_payload(IPProto::ICMP, ICMPType::EchoReply as u8, 0), Ok(()));
+        assert_eq!(check_payload(IPProto::UDP, ICMPType::Unreachable as u8, 0), Ok(()));
     }
 }
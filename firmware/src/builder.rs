@@ -0,0 +1,301 @@
+/*
+ * Composition of outgoing packets
+ *
+ * Headers and payload are written in place, and lengths and checksums are filled by finish.
+ */
+
+use crate::buf::{BufHandle, EthType};
+use crate::data::arp::ARP;
+use crate::packet::*;
+use crate::routing::IPAddr;
+
+enum Transport {
+    None,
+    UDP { src_port: u16, dest_port: u16 },
+    ICMP { r#type: u8, code: u8, rest: [u8; 4] },
+}
+
+struct IPv4Header {
+    src: IPAddr,
+    dest: IPAddr,
+    ttl: Option<u8>,
+    tos: u8,
+    options_len: usize,
+}
+
+/// Identification of the next datagram originated by the router
+static mut NEXT_IP_ID: u16 = 0;
+
+/**
+ * Datagrams from the router share one counter, so that fragments of different
+ * datagrams to the same destination are never mixed up (RFC 6864)
+ */
+fn next_ip_id() -> u16 {
+    unsafe {
+        let id = NEXT_IP_ID;
+        NEXT_IP_ID = id.wrapping_add(1);
+        id
+    }
+}
+
+/**
+ * Writes a packet layer by layer, either into the send cell of a BufHandle,
+ * Ethernet header included, or as a bare IPv4 datagram into any buffer.
+ *
+ * Layers are added in order: ethernet, then arp or ipv4 with its options,
+ * then udp or icmp, then the payload.
+ */
+pub struct PacketBuilder {
+    frame: Option<EthernetFrame>,
+    bytes: Bytes,
+    arp: bool,
+    ip: Option<IPv4Header>,
+    transport: Transport,
+    /// Where the UDP or ICMP header starts
+    transport_at: usize,
+    /// End of what was written so far
+    at: usize,
+}
+
+impl PacketBuilder {
+    /// Builds a frame in the cell of handle, usually the send buffer
+    pub fn new(handle: &BufHandle) -> Self {
+        Self::with(Some(handle.frame()), handle.room())
+    }
+
+    /// Builds a bare datagram in bytes, to be sent later on
+    pub fn datagram(bytes: Bytes) -> Self {
+        Self::with(None, bytes)
+    }
+
+    fn with(frame: Option<EthernetFrame>, bytes: Bytes) -> Self {
+        Self {
+            frame,
+            bytes,
+            arp: false,
+            ip: None,
+            transport: Transport::None,
+            transport_at: 0,
+            at: 0,
+        }
+    }
+
+    /// Addresses the frame from src to dest, tagged with the VLAN of port
+    pub fn ethernet(&mut self, src: [u8; 6], dest: [u8; 6], port: u8) -> &mut Self {
+        let frame = self.frame.as_ref().expect("no Ethernet header in a bare datagram");
+        frame.set_src(src);
+        frame.set_dest(dest);
+        frame.set_port(port);
+        self
+    }
+
+    pub fn arp(&mut self, arp: &ARP) -> &mut Self {
+        assert!(self.at == 0, "ARP after another layer");
+
+        ArpPacket::allocate(self.bytes).write(arp);
+        self.arp = true;
+        self.at = ARP_LEN;
+        self
+    }
+
+    pub fn ipv4(&mut self, src: IPAddr, dest: IPAddr) -> &mut Self {
+        assert!(self.at == 0, "IPv4 after another layer");
+
+        self.ip = Some(IPv4Header {
+            src,
+            dest,
+            ttl: None,
+            tos: 0,
+            options_len: 0,
+        });
+        self.at = 20;
+        self.transport_at = self.at;
+        self
+    }
+
+    /// Overrides the default TTL of the datagram
+    pub fn ttl(&mut self, ttl: u8) -> &mut Self {
+        self.ip.as_mut().expect("TTL without IPv4").ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the type of service of the datagram, 0 by default
+    pub fn tos(&mut self, tos: u8) -> &mut Self {
+        self.ip.as_mut().expect("ToS without IPv4").tos = tos;
+        self
+    }
+
+    /**
+     * Adds IPv4 options, written by write into the room it is given.
+     * write returns the length of the options, padded to whole words.
+     */
+    pub fn ip_options<F: FnOnce(&Bytes) -> usize>(&mut self, write: F) -> &mut Self {
+        let len = write(&self.bytes.from(self.at));
+        assert!(len % 4 == 0, "unpadded IPv4 options");
+
+        let ip = self.ip.as_mut().expect("options without IPv4");
+        assert!(self.transport_at == self.at, "options after the payload");
+
+        ip.options_len += len;
+        self.at += len;
+        self.transport_at = self.at;
+        self
+    }
+
+    pub fn udp(&mut self, src_port: u16, dest_port: u16) -> &mut Self {
+        self.transport(Transport::UDP { src_port, dest_port }, UDP_HEADER_LEN)
+    }
+
+    /// Adds an ICMP header, rest being the 4 bytes after the checksum
+    pub fn icmp(&mut self, r#type: u8, code: u8, rest: [u8; 4]) -> &mut Self {
+        self.transport(Transport::ICMP { r#type, code, rest }, ICMP_HEADER_LEN)
+    }
+
+    fn transport(&mut self, transport: Transport, header_len: usize) -> &mut Self {
+        assert!(self.ip.is_some() && self.transport_at == self.at, "transport header out of order");
+
+        self.transport = transport;
+        self.at += header_len;
+        self
+    }
+
+    pub fn payload(&mut self, data: &[u8]) -> &mut Self {
+        self.bytes.set_slice(self.at, data);
+        self.at += data.len();
+        self
+    }
+
+    /// Same as payload, from buffer memory
+    pub fn payload_from(&mut self, data: &Bytes) -> &mut Self {
+        self.bytes.copy_from(self.at, data);
+        self.at += data.len();
+        self
+    }
+
+    /**
+     * Fills the lengths and checksums of every layer, and the ethertype of the frame.
+     *
+     * Returns the length of the packet after the Ethernet header, as expected by write_payload_len
+     */
+    pub fn finish(&mut self) -> u16 {
+        let segment = self.bytes.slice(self.transport_at, self.at - self.transport_at);
+
+        if let Some(header) = self.ip.as_ref() {
+            let proto = match self.transport {
+                Transport::UDP { src_port, dest_port } => {
+                    let udp = UdpDatagram::allocate(segment);
+                    udp.set_header(src_port, dest_port);
+                    udp.fill_chksum(&header.src, &header.dest);
+                    IPProto::UDP
+                },
+                Transport::ICMP { r#type, code, rest } => {
+                    let icmp = IcmpMessage::allocate(segment);
+                    icmp.set_type(r#type);
+                    icmp.set_code(code);
+                    icmp.set_rest(rest);
+                    icmp.fill_chksum();
+                    IPProto::ICMP
+                },
+                Transport::None => panic!("IPv4 without a transport header"),
+            };
+
+            // All fields first, so that the header is checksummed once
+            let mut ip = Ipv4Packet::new_unchecked(self.bytes);
+            ip.outgoing_with_options(proto, segment.len() as u16, header.src, header.dest, header.options_len as u16);
+            if let Some(ttl) = header.ttl {
+                ip.set_ttl(ttl);
+            }
+            ip.set_tos(header.tos);
+            ip.set_id(next_ip_id());
+            ip.fill_chksum();
+        }
+
+        if let Some(frame) = self.frame.as_ref() {
+            if self.arp {
+                frame.set_eth_type(EthType::ARP);
+            } else if self.ip.is_some() {
+                frame.set_eth_type(EthType::IPv4);
+            }
+        }
+
+        self.at as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp() {
+        let mut buf = [0u8; 64];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+
+        let len = PacketBuilder::datagram(bytes)
+            .ipv4([192, 168, 0, 1], [224, 0, 0, 9])
+            .ttl(1)
+            .tos(0xC0)
+            .udp(520, 520)
+            .payload(&[2, 2, 0, 0])
+            .finish();
+        assert_eq!(len, 32);
+
+        let ip = Ipv4Packet::new_checked(bytes).ok().unwrap();
+        assert_eq!(ip.bytes().len(), 32);
+        assert_eq!(ip.ttl(), 1);
+        assert_eq!(ip.bytes().u8_at(1), 0xC0);
+        assert!(ip.proto() == IPProto::UDP as u8);
+
+        let udp = ip.udp().unwrap();
+        assert_eq!(udp.len(), 12);
+        assert_eq!(udp.dest_port(), 520);
+        assert!(udp.verify(&ip.src(), &ip.dest()));
+        assert_eq!(udp.payload().u8_at(0), 2);
+    }
+
+    #[test]
+    fn test_icmp_with_options() {
+        let mut buf = [0u8; 64];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+
+        let len = PacketBuilder::datagram(bytes)
+            .ipv4([10, 0, 0, 1], [10, 0, 0, 2])
+            .ip_options(|room| {
+                room.set_slice(0, &[IP_OPT_RECORD_ROUTE, 3, 4, IP_OPT_END]);
+                4
+            })
+            .icmp(0, 0, [0, 1, 0, 2])
+            .payload(&[0xAB; 5])
+            .finish();
+        assert_eq!(len, 24 + 8 + 5);
+
+        let ip = Ipv4Packet::new_checked(bytes).ok().unwrap();
+        assert_eq!(ip.header_len(), 24);
+        assert_eq!(ip.options().count(), 1);
+
+        let icmp = ip.icmp().unwrap();
+        assert_eq!(icmp.rest(), [0, 1, 0, 2]);
+        assert_eq!(icmp.data().len(), 5);
+        assert!(icmp.verify());
+    }
+
+    #[test]
+    fn test_ip_id() {
+        let mut buf = [0u8; 64];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+
+        let send = || {
+            PacketBuilder::datagram(bytes)
+                .ipv4([10, 0, 0, 1], [10, 0, 0, 2])
+                .udp(520, 520)
+                .finish();
+
+            let ip = Ipv4Packet::new_checked(bytes).ok().unwrap();
+            assert_eq!(ip.bytes().u8_at(1), 0);
+            ip.id()
+        };
+
+        // Consecutive datagrams are told apart
+        assert_ne!(send(), send());
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * Internet checksum (RFC 1071), with incremental updates (RFC 1624)
+ *
+ * Values are in host byte order, and have to be converted with to_be before
+ * being written into a header.
+ */
+
+use crate::routing::IPAddr;
+
+/// Running one's complement sum over data in network byte order
+#[derive(Clone, Copy, Default)]
+pub struct Checksum {
+    sum: u64,
+    /// If an odd number of bytes was added, so that the next one is the low half of a word
+    odd: bool,
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add_byte(&mut self, byte: u8) {
+        self.sum += if self.odd { byte as u64 } else { (byte as u64) << 8 };
+        self.odd = !self.odd;
+    }
+
+    /// Adds len bytes at ptr, read volatilely so that packet buffers can be summed in place
+    pub fn add_raw(&mut self, ptr: *const u8, len: usize) {
+        for i in 0..len {
+            self.add_byte(unsafe { core::ptr::read_volatile(ptr.offset(i as isize)) });
+        }
+    }
+
+    pub fn add_slice(&mut self, data: &[u8]) {
+        for byte in data.iter() {
+            self.add_byte(*byte);
+        }
+    }
+
+    pub fn add_u16(&mut self, value: u16) {
+        self.add_byte((value >> 8) as u8);
+        self.add_byte(value as u8);
+    }
+
+    pub fn add_addr(&mut self, addr: &IPAddr) {
+        self.add_slice(addr);
+    }
+
+    /// The value of the checksum field
+    pub fn finish(&self) -> u16 {
+        !fold(self.sum)
+    }
+
+    /// If the data summed, checksum field included, is intact
+    pub fn is_valid(&self) -> bool {
+        fold(self.sum) == 0xFFFF
+    }
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while (sum >> 16) > 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+/// Checksum of len bytes at ptr, whose checksum field is zeroed
+pub fn checksum(ptr: *const u8, len: usize) -> u16 {
+    let mut sum = Checksum::new();
+    sum.add_raw(ptr, len);
+    sum.finish()
+}
+
+/// Checks len bytes at ptr, checksum field included
+pub fn verify(ptr: *const u8, len: usize) -> bool {
+    let mut sum = Checksum::new();
+    sum.add_raw(ptr, len);
+    sum.is_valid()
+}
+
+/// Starts the checksum of a UDP or TCP segment of len bytes with its pseudo-header
+pub fn pseudo_header(src: &IPAddr, dest: &IPAddr, proto: u8, len: u16) -> Checksum {
+    let mut sum = Checksum::new();
+    sum.add_addr(src);
+    sum.add_addr(dest);
+    sum.add_u16(proto as u16);
+    sum.add_u16(len);
+    sum
+}
+
+/// Adjusts chksum for a 16-bit word of the data changing from old to new: HC' = ~(~HC + ~m + m')
+pub fn update(chksum: u16, old: u16, new: u16) -> u16 {
+    !fold(!chksum as u64 + !old as u64 + new as u64)
+}
+
+/// Adjusts chksum for an address changing from old to new, e.g. when rewritten by NAT
+pub fn update_addr(chksum: u16, old: &IPAddr, new: &IPAddr) -> u16 {
+    let hi = update(chksum, u16::from_be_bytes([old[0], old[1]]), u16::from_be_bytes([new[0], new[1]]));
+    update(hi, u16::from_be_bytes([old[2], old[3]]), u16::from_be_bytes([new[2], new[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // RFC 1071 section 3 example
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(data.as_ptr(), data.len()), !0xddf2);
+
+        // Odd lengths are padded with a zero byte
+        let odd = [0x45, 0x00, 0x12];
+        assert_eq!(checksum(odd.as_ptr(), odd.len()), !0x5700);
+
+        // Splitting at an odd offset does not change the sum
+        let mut split = Checksum::new();
+        split.add_slice(&data[0..3]);
+        split.add_slice(&data[3..]);
+        assert_eq!(split.finish(), checksum(data.as_ptr(), data.len()));
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x54, 0x1c, 0x46, 0x40, 0x00,
+            0x40, 0x01, 0x00, 0x00, 0xc0, 0xa8, 0x00, 0x01,
+            0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        let sum = checksum(header.as_ptr(), header.len());
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+        assert!(verify(header.as_ptr(), header.len()));
+
+        header[8] ^= 0x10;
+        assert!(!verify(header.as_ptr(), header.len()));
+    }
+
+    #[test]
+    fn test_pseudo_header() {
+        let src = [192, 168, 0, 1];
+        let dest = [224, 0, 0, 9];
+        let mut udp = [0x02, 0x08, 0x02, 0x08, 0x00, 0x0d, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x01];
+
+        let mut sum = pseudo_header(&src, &dest, 0x11, udp.len() as u16);
+        sum.add_slice(&udp);
+        udp[6..8].copy_from_slice(&sum.finish().to_be_bytes());
+
+        let mut check = pseudo_header(&src, &dest, 0x11, udp.len() as u16);
+        check.add_slice(&udp);
+        assert!(check.is_valid());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut header = [
+            0x45, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x00, 0x00,
+            0x40, 0x06, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01,
+            0x0a, 0x00, 0x00, 0x02,
+        ];
+
+        for ttl in (1..=255u8).rev() {
+            header[8] = ttl;
+            header[10..12].copy_from_slice(&[0, 0]);
+            let before = checksum(header.as_ptr(), header.len());
+
+            let old = u16::from_be_bytes([header[8], header[9]]);
+            header[8] -= 1;
+            let new = u16::from_be_bytes([header[8], header[9]]);
+            let updated = update(before, old, new);
+
+            header[10..12].copy_from_slice(&updated.to_be_bytes());
+            assert!(verify(header.as_ptr(), header.len()));
+        }
+
+        header[10..12].copy_from_slice(&[0, 0]);
+        let before = checksum(header.as_ptr(), header.len());
+        let new_src = [172, 16, 255, 254];
+        let updated = update_addr(before, &[10, 0, 0, 1], &new_src);
+
+        header[12..16].copy_from_slice(&new_src);
+        header[10..12].copy_from_slice(&updated.to_be_bytes());
+        assert!(verify(header.as_ptr(), header.len()));
+    }
+}
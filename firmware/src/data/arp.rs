@@ -1,4 +1,5 @@
-use crate::buf::EthType;
+use crate::buf::{BufHandle, EthType};
+use crate::builder::PacketBuilder;
 
 #[repr(u16)]
 pub enum HType {
@@ -6,6 +7,7 @@ pub enum HType {
 }
 
 #[repr(u16)]
+#[derive(PartialEq)]
 pub enum Oper {
     Req = 0x0100,
     Reply = 0x0200,
@@ -23,3 +25,27 @@ pub struct ARP {
     pub tha: [u8; 6],
     pub tpa: [u8; 4],
 }
+
+impl ARP {
+    pub fn request(sha: [u8; 6], spa: [u8; 4], tha: [u8; 6], tpa: [u8; 4]) -> ARP {
+        ARP {
+            htype: HType::Eth,
+            ptype: EthType::IPv4,
+            hlen: 6,
+            plen: 4,
+            op: Oper::Req,
+            sha, spa, tha, tpa,
+        }
+    }
+
+    /// Writes the packet into the send buffer, and sends it through port to dest
+    pub fn send(self, snd_handle: &mut BufHandle, port: u8, dest: [u8; 6]) {
+        let len = PacketBuilder::new(snd_handle)
+            .ethernet(self.sha, dest, port)
+            .arp(&self)
+            .finish();
+
+        snd_handle.write_payload_len(len);
+        snd_handle.send();
+    }
+}
@@ -3,20 +3,29 @@
 
 #![no_std]
 
+// Unit tests run on the host, where util::hprint_char and cmd::Cmd::send are no-ops
 #[cfg(test)]
 #[macro_use]
 extern crate std;
 
 mod util;
+mod checksum;
+mod packet;
+mod builder;
 mod buf;
 mod data;
 mod nc;
 mod cmd;
 mod routing;
 mod forward;
+mod hold;
+mod iface;
+mod reasm;
 
 use buf::*;
 use buf::icmp::*;
+use packet::*;
+use builder::PacketBuilder;
 use data::arp::*;
 use routing::*;
 use forward::*;
@@ -42,7 +51,22 @@ const MACS: [[u8; 6]; 5] = [
     [0x9c, 0xeb, 0, 0, 0, 4],
 ];
 
+const IFACES: [iface::IfaceConfig; 5] = [iface::IfaceConfig::DEFAULT; 5];
+
+/// Permanent neighboors: IP, MAC, port, and whether to pin them into hardware
+const STATIC_NEIGHBOORS: [([u8; 4], [u8; 6], u8, bool); 0] = [];
+
 const REFRESH_HOLDING_TIME_US: u64 = 5_000_000;
+const STATS_INTERVAL_US: u64 = 60_000_000;
+
+/// Neighboor cache buckets, 4 entries each
+const NC_BUCKETS: usize = 128;
+/// Next hops used by at least this many routes are pinned into hardware
+const NC_PIN_MIN_ROUTES: usize = 8;
+
+/// Ethernet group of 224.0.0.1
+const ALL_SYSTEMS_MAC: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+
 
 extern "C" {
     #[no_mangle]
@@ -56,8 +80,486 @@ static mut rule_count: usize = 0;
 #[no_mangle]
 static mut rule_updated: bool = false;
 
+/// Room to build replies larger than the send buffer, kept in RAM by the .bss rule of firmware.ld
+static mut REPLY_SCRATCH: [u8; reasm::REASM_DATAGRAM_MAX] = [0; reasm::REASM_DATAGRAM_MAX];
+
+/// The reply scratch buffer, only used by send_icmp_reply, which never runs reentrantly
+fn reply_scratch() -> Bytes {
+    Bytes::new(unsafe { REPLY_SCRATCH.as_mut_ptr() }, reasm::REASM_DATAGRAM_MAX)
+}
+
 #[no_mangle]
-static mut ncache_ptr: *const nc::NeighboorCache = core::ptr::null();
+static mut ncache_ptr: *mut nc::NeighboorCache<NC_BUCKETS> = core::ptr::null_mut();
+
+/// Cuckoo keys and values are stored in the byte order of the hardware
+fn hw_order(ip: &IPAddr) -> IPAddr {
+    [ip[3], ip[2], ip[1], ip[0]]
+}
+
+/// If packets sent to dest are for the router itself
+fn is_local(dest: &IPAddr) -> bool {
+    IPS.contains(dest) || is_broadcast(dest)
+}
+
+/// If dest is a multicast group, the limited broadcast or the broadcast of one of our subnets
+fn is_broadcast(dest: &IPAddr) -> bool {
+    if *dest == [255, 255, 255, 255] || dest[0] & 0xF0 == 224 {
+        return true;
+    }
+
+    IPS.iter().zip(IFACES.iter()).any(|(addr, iface)| {
+        // Point-to-point subnets have no broadcast address
+        if iface.prefix_len >= 31 {
+            return false;
+        }
+
+        let host_mask = !0u32 >> iface.prefix_len as u32;
+        iface.on_link(addr, dest) && u32::from_be_bytes(*dest) & host_mask == host_mask
+    })
+}
+
+/**
+ * Sends an ICMP error about the IPv4 datagram in orig back to its source,
+ * which is reached through port via the neighboor dst_mac.
+ * link_broadcast tells if orig came in a broadcast or multicast frame.
+ * Silently gives up when the limiter suppresses the error.
+ */
+fn send_icmp_error(
+    snd_handle: &mut BufHandle,
+    limiter: &mut ErrorLimiter<5>,
+    port: u8,
+    dst_mac: [u8; 6],
+    link_broadcast: bool,
+    orig: &Bytes,
+    r#type: ICMPType,
+    code: u8,
+    rest: [u8; 4],
+) {
+    let orig_ip = Ipv4Packet::new_unchecked(*orig);
+    if !limiter.check(port, r#type, orig, is_broadcast, link_broadcast, cur_time()) {
+        return;
+    }
+
+    let len = PacketBuilder::new(snd_handle)
+        .ethernet(MACS[port as usize], dst_mac, port)
+        .ipv4(IPS[port as usize], orig_ip.src())
+        .icmp(r#type as u8, code, rest)
+        .payload_from(&error_quote(orig))
+        .finish();
+
+    snd_handle.write_payload_len(len);
+    snd_handle.send();
+}
+
+fn pin_next_hops<const BUCKETS: usize>(ncache: &mut nc::NeighboorCache<{BUCKETS}>, rules: &[Rule]) {
+    let mut pins = [[0; 4]; nc::NC_PIN_MAX];
+    let cnt = busiest_next_hops(rules, NC_PIN_MIN_ROUTES, &mut pins);
+    ncache.set_pinned(&pins[0..cnt]);
+}
+
+/**
+ * Answers with the first len bytes of the echo, timestamp or information request req,
+ * carried by the datagram req_ip received through port, to dst. The reply is built directly
+ * in the send buffer, with the recorded route and timestamps of the request.
+ * received is the receive timestamp of timestamp requests.
+ */
+fn send_icmp_reply(
+    snd_handle: &mut BufHandle,
+    limiter: &mut ErrorLimiter<5>,
+    port: u8,
+    dst: IPAddr,
+    dst_mac: [u8; 6],
+    req_ip: &Ipv4Packet,
+    req: &IcmpMessage,
+    len: u16,
+    r#type: ICMPType,
+    received: Option<u32>,
+) {
+    // Replies to reassembled requests may need to be fragmented, which is done from a scratch buffer
+    let fits = req_ip.header_len() + len <= IFACES[port as usize].mtu;
+    let scratch = reply_scratch();
+
+    let mut builder = if fits { PacketBuilder::new(snd_handle) } else { PacketBuilder::datagram(scratch) };
+    if fits {
+        builder.ethernet(MACS[port as usize], dst_mac, port);
+    }
+
+    // Identifier, sequence number and data are echoed as is
+    builder
+        .ipv4(IPS[port as usize], dst)
+        .ip_options(|room| req_ip.copy_reply_options(room) as usize)
+        .icmp(r#type as u8, 0, req.rest());
+
+    let data = req.data().slice(0, len as usize - ICMP_HEADER_LEN);
+    match received {
+        // The originate timestamp is kept
+        Some(received) => builder
+            .payload_from(&data.slice(0, 4))
+            .payload(&received.to_be_bytes())
+            .payload(&timestamp().to_be_bytes()),
+        None => builder.payload_from(&data),
+    };
+
+    let frame_len = builder.finish();
+
+    if !fits {
+        send_datagram(snd_handle, limiter, &Ipv4Packet::new_unchecked(scratch), port, dst_mac, port, dst_mac, false);
+        return;
+    }
+
+    snd_handle.write_payload_len(frame_len);
+    snd_handle.send();
+}
+
+/**
+ * Advertises the address of port as a default router, either multicasted
+ * to all systems or unicasted to a soliciting host.
+ */
+fn send_router_ad(snd_handle: &mut BufHandle, port: u8, dst: [u8; 4], dst_mac: [u8; 6]) {
+    let iface = &IFACES[port as usize];
+
+    let mut builder = PacketBuilder::new(snd_handle);
+    builder
+        .ethernet(MACS[port as usize], dst_mac, port)
+        .ipv4(IPS[port as usize], dst);
+    if dst == ALL_SYSTEMS {
+        builder.ttl(1);
+    }
+
+    router_ad(&mut builder, IPS[port as usize], iface.ad_lifetime, iface.ad_preference);
+    let len = builder.finish();
+
+    snd_handle.write_payload_len(len);
+    snd_handle.send();
+}
+
+/**
+ * Sends the IPv4 datagram ip through port to the neighboor dst_mac,
+ * fragmented to the MTU of the interface. Datagrams that may not be fragmented are
+ * reported with "fragmentation needed" to the neighboor src_mac they came from through src_port,
+ * unless they came in a link_broadcast frame.
+ * ip must not be in the send buffer.
+ */
+fn send_datagram(
+    snd_handle: &mut BufHandle,
+    limiter: &mut ErrorLimiter<5>,
+    ip: &Ipv4Packet,
+    port: u8,
+    dst_mac: [u8; 6],
+    src_port: u8,
+    src_mac: [u8; 6],
+    link_broadcast: bool,
+) {
+    let mtu = IFACES[port as usize].mtu as usize;
+    let header_len = ip.header_len() as usize;
+    let payload_len = ip.payload_len() as usize;
+
+    if header_len + payload_len > mtu && ip.dont_fragment() {
+        let mtu = mtu as u16;
+        send_icmp_error(
+            snd_handle,
+            limiter,
+            src_port,
+            src_mac,
+            link_broadcast,
+            &ip.bytes(),
+            ICMPType::Unreachable,
+            ICMP_UNREACH_NEEDFRAG,
+            [0, 0, (mtu >> 8) as u8, mtu as u8],
+        );
+        return;
+    }
+
+    // Later fragments never have a longer header than the first one
+    let chunk = if header_len + payload_len > mtu { mtu.saturating_sub(header_len) / 8 * 8 } else { payload_len };
+    if chunk == 0 && payload_len != 0 {
+        return;
+    }
+
+    let mut offset = 0;
+    loop {
+        let len = core::cmp::min(chunk, payload_len - offset);
+        let more = offset + len < payload_len;
+        let frag_len = ip.write_fragment(&snd_handle.room(), offset, len, more);
+
+        snd_handle.write_dest(dst_mac);
+        snd_handle.write_src(MACS[port as usize]);
+        snd_handle.write_port(port);
+        snd_handle.write_eth_type(EthType::IPv4);
+        snd_handle.write_payload_len(frag_len);
+        snd_handle.send();
+
+        offset += len;
+        if !more {
+            break;
+        }
+    }
+}
+
+/// Forwards the datagram in buf_handle to the neighboor mac on port, fragmenting it if needed
+fn forward(buf_handle: &mut BufHandle, snd_handle: &mut BufHandle, limiter: &mut ErrorLimiter<5>, mac: [u8; 6], port: u8) {
+    if buf_handle.payload_len() <= IFACES[port as usize].mtu {
+        buf_handle.write_dest(mac);
+        buf_handle.write_src(MACS[port as usize]);
+        buf_handle.write_port(port);
+        buf_handle.send();
+    } else {
+        let (from_port, from_mac, from_broadcast) = (buf_handle.port(), buf_handle.src(), buf_handle.link_broadcast());
+        let ip = Ipv4Packet::new_unchecked(buf_handle.payload());
+        send_datagram(snd_handle, limiter, &ip, port, mac, from_port, from_mac, from_broadcast);
+        buf_handle.drop();
+    }
+}
+
+/// Holds the datagram in buf_handle until next_hop is resolved, or drops it if the hold queue is full
+fn hold_datagram(hold: &mut hold::HoldQueue, buf_handle: &BufHandle, next_hop: IPAddr) {
+    let result = hold.hold(
+        next_hop,
+        &buf_handle.payload(),
+        buf_handle.port(),
+        buf_handle.src(),
+        buf_handle.link_broadcast(),
+        cur_time(),
+    );
+
+    if let Err(reason) = result {
+        hprint("Hold dropped for ");
+        hprint_ip(&next_hop);
+        hprint(match reason {
+            hold::HoldDrop::TooLarge => ": too large\n\r",
+            hold::HoldDrop::NextHopFull => ": next hop full\n\r",
+            hold::HoldDrop::PoolFull => ": pool full\n\r",
+        });
+    }
+}
+
+/**
+ * Sends the datagram in buf_handle, bound to dest, to the next hop of route:
+ * right away if the neighboor is resolved, or once it is, from the hold queue.
+ */
+fn send_to_next_hop<const BUCKETS: usize>(
+    buf_handle: &mut BufHandle,
+    snd_handle: &mut BufHandle,
+    limiter: &mut ErrorLimiter<5>,
+    ncache: &mut nc::NeighboorCache<{BUCKETS}>,
+    hold: &mut hold::HoldQueue,
+    dest: &IPAddr,
+    route: &Route,
+) {
+    let next_hop = route.next;
+
+    if next_hop == [255, 255, 255, 255] {
+        // Ignored route
+        buf_handle.drop();
+    } else if let Some(idx) = ncache.lookup(&next_hop) {
+        // Still forwarded in software if every hardware slot is pinned
+        let _ = ncache.write_hardware(idx, cur_time());
+        let result = ncache.get(idx);
+        let _ = unsafe { _cuckoo.set_egress(&hw_order(dest), Egress::new(result.mac, result.port)) };
+
+        forward(buf_handle, snd_handle, limiter, result.mac, result.port);
+    } else {
+        // Only ask on the interface the next hop is reached through
+        let port = route.if_index + 1;
+
+        match ncache.resolve(next_hop, port, cur_time()) {
+            nc::Resolve::Request(evicted) => {
+                if let Some(evicted) = evicted {
+                    unsafe { _cuckoo.forget_egress(&evicted.mac) };
+                }
+
+                ARP::request(MACS[port as usize], IPS[port as usize], [0; 6], next_hop)
+                    .send(snd_handle, port, [255; 6]);
+                hold_datagram(hold, buf_handle, next_hop);
+            },
+            nc::Resolve::Pending => {
+                hold_datagram(hold, buf_handle, next_hop);
+            },
+            nc::Resolve::Failed => {
+                send_icmp_error(
+                    snd_handle,
+                    limiter,
+                    buf_handle.port(),
+                    buf_handle.src(),
+                    buf_handle.link_broadcast(),
+                    &buf_handle.payload(),
+                    ICMPType::Unreachable,
+                    ICMP_UNREACH_HOST,
+                    [0; 4],
+                );
+            },
+        }
+
+        buf_handle.drop();
+    }
+}
+
+/**
+ * Forwards in software a datagram punted by the hardware, along its route if there is one.
+ * Its TTL is expected to have been checked, and is decremented here.
+ */
+fn slow_forward<const BUCKETS: usize>(
+    buf_handle: &mut BufHandle,
+    snd_handle: &mut BufHandle,
+    limiter: &mut ErrorLimiter<5>,
+    ncache: &mut nc::NeighboorCache<{BUCKETS}>,
+    hold: &mut hold::HoldQueue,
+    route: Option<Route>,
+) {
+    let mut ip = Ipv4Packet::new_unchecked(buf_handle.payload());
+    let dest = ip.dest();
+
+    let route = match route {
+        Some(route) => route,
+        None => {
+            hprint("No route: ");
+            hprint_ip(&dest);
+            hprint(" -> !\n\r");
+
+            send_icmp_error(
+                snd_handle,
+                limiter,
+                buf_handle.port(),
+                buf_handle.src(),
+                buf_handle.link_broadcast(),
+                &ip.bytes(),
+                ICMPType::Unreachable,
+                ICMP_UNREACH_NET,
+                [0; 4],
+            );
+            buf_handle.drop();
+            return;
+        },
+    };
+
+    let port = buf_handle.port();
+    if should_redirect(&IFACES[port as usize], &IPS[port as usize], port, &ip.src(), &route) {
+        send_icmp_error(
+            snd_handle,
+            limiter,
+            port,
+            buf_handle.src(),
+            buf_handle.link_broadcast(),
+            &ip.bytes(),
+            ICMPType::Redirect,
+            ICMP_REDIRECT_HOST,
+            route.next,
+        );
+    }
+
+    ip.decrement_ttl();
+    send_to_next_hop(buf_handle, snd_handle, limiter, ncache, hold, &dest, &route);
+}
+
+/// Sends a packet taken out of the hold queue to its now resolved next hop
+fn send_held(snd_handle: &mut BufHandle, limiter: &mut ErrorLimiter<5>, pkt: &hold::HeldPacket, mac: [u8; 6], port: u8) {
+    if pkt.len > IFACES[port as usize].mtu {
+        let ip = Ipv4Packet::new_unchecked(pkt.datagram());
+        send_datagram(snd_handle, limiter, &ip, port, mac, pkt.port, pkt.src, pkt.link_broadcast);
+        return;
+    }
+
+    snd_handle.room().copy_from(0, &pkt.datagram());
+
+    snd_handle.write_dest(mac);
+    snd_handle.write_src(MACS[port as usize]);
+    snd_handle.write_port(port);
+    snd_handle.write_eth_type(EthType::IPv4);
+    snd_handle.write_payload_len(pkt.len);
+    snd_handle.send();
+}
+
+/**
+ * If a request received on port, configured as iface with address addr, for an address
+ * that is not ours, should be answered with our MAC.
+ * Only done on proxy-ARP interfaces, for on-link requesters asking about targets
+ * that lookup_route routes through another interface.
+ */
+fn should_proxy_arp<F: FnOnce(&IPAddr) -> Option<Route>>(
+    iface: &iface::IfaceConfig,
+    addr: &IPAddr,
+    port: u8,
+    arp: &ARP,
+    lookup_route: F,
+) -> bool {
+    if !iface.proxy_arp {
+        return false;
+    }
+
+    // Probes and gratuitous requests
+    if arp.spa == [0, 0, 0, 0] || arp.spa == arp.tpa {
+        return false;
+    }
+
+    // The requester could not reach us directly, nor the target within its own subnet
+    if !iface.on_link(addr, &arp.spa) || iface.on_link(addr, &arp.tpa) {
+        return false;
+    }
+
+    match lookup_route(&arp.tpa) {
+        Some(route) => route.next != [255, 255, 255, 255] && route.if_index + 1 != port,
+        None => false,
+    }
+}
+
+/**
+ * If a packet from src, received on port, configured as iface with address addr,
+ * and forwarded along route, warrants a host redirect.
+ * RFC 1812 5.2.7.2: the packet leaves through the interface it came from,
+ * and its source is on the subnet of that interface.
+ */
+fn should_redirect(iface: &iface::IfaceConfig, addr: &IPAddr, port: u8, src: &IPAddr, route: &Route) -> bool {
+    if !iface.send_redirects {
+        return false;
+    }
+
+    if route.next == [255, 255, 255, 255] || route.if_index + 1 != port {
+        return false;
+    }
+
+    // The source already is the first hop
+    if route.next == *src {
+        return false;
+    }
+
+    iface.on_link(addr, src) && iface.on_link(addr, &route.next)
+}
+
+/**
+ * Programs the address of an interface into the hardware.
+ * The returned check probes for conflicts, then announces the address with gratuitous ARPs.
+ */
+fn configure_iface(vlan: u8, now: u64) -> iface::AddrCheck {
+    Cmd {
+        op: Op::SetIP,
+        idx: vlan,
+        data: [
+            IPS[vlan as usize][3], IPS[vlan as usize][2], IPS[vlan as usize][1], IPS[vlan as usize][0],
+            0, 0,
+        ],
+    }.send();
+
+    Cmd {
+        op: Op::SetMAC,
+        idx: vlan,
+        data: [
+            MACS[vlan as usize][5],
+            MACS[vlan as usize][4],
+            MACS[vlan as usize][3],
+            MACS[vlan as usize][2],
+            MACS[vlan as usize][1],
+            MACS[vlan as usize][0],
+        ],
+    }.send();
+
+    iface::AddrCheck::new(now)
+}
+
+fn send_announcement(snd_handle: &mut BufHandle, port: u8) {
+    let port_idx = port as usize;
+    ARP::request(MACS[port_idx], IPS[port_idx], [0; 6], IPS[port_idx])
+        .send(snd_handle, port, [255; 6]);
+}
 
 unsafe fn reset_cuckoo() {
     for i in ((4 << 20) / 8)..((8 << 20) / 8) {
@@ -87,12 +589,17 @@ pub unsafe extern "C" fn _start() -> ! {
     };
 
     let mut rules: [Rule; 8192] = core::mem::uninitialized();
-    let mut ncache = nc::NeighboorCache::default();
+    let mut ncache = nc::NeighboorCache::<NC_BUCKETS>::new();
+    let mut hold = hold::HoldQueue::new();
+    let mut reasm = reasm::Reassembly::new();
 
     let mut pending_refresh: Option<u64> = None;
+    let mut next_stats = ct + STATS_INTERVAL_US;
+    let mut icmp_limiter = ErrorLimiter::<5>::new();
+    let mut ip_drops = IPv4Drops::default();
 
     rules_ptr = core::mem::transmute(&rules as *const _);
-    ncache_ptr = &ncache;
+    ncache_ptr = &mut ncache;
 
     rules[0] = Rule {
         prefix: [0,0,0,0],
@@ -116,32 +623,24 @@ pub unsafe extern "C" fn _start() -> ! {
 
     let mut routing_alloc = TrieBuf::<16384>::new();
     let mut routing_table = Trie::from_rules(&mut routing_alloc, &mut rules[0..rule_count]);
+    pin_next_hops(&mut ncache, &rules[0..rule_count]);
+
+    for (ip, mac, port, pin) in STATIC_NEIGHBOORS.iter() {
+        if let Err(_) = ncache.add_static(*ip, *mac, *port, *pin, ct) {
+            hprint("Failed to add static neighboor ");
+            hprint_ip(ip);
+            hprint("\n\r");
+        }
+    }
 
     // Initialize
+    let mut addr_checks = [iface::AddrCheck::new(ct); 5];
     for vlan in 0..=4 {
-        Cmd {
-            op: Op::SetIP,
-            idx: vlan,
-            data: [
-                IPS[vlan as usize][3], IPS[vlan as usize][2], IPS[vlan as usize][1], IPS[vlan as usize][0],
-                0, 0,
-            ],
-        }.send();
-
-        Cmd {
-            op: Op::SetMAC,
-            idx: vlan,
-            data: [
-                MACS[vlan as usize][5],
-                MACS[vlan as usize][4],
-                MACS[vlan as usize][3],
-                MACS[vlan as usize][2],
-                MACS[vlan as usize][1],
-                MACS[vlan as usize][0],
-            ],
-        }.send();
+        addr_checks[vlan as usize] = configure_iface(vlan, ct);
     }
 
+    let mut advert_timers = [iface::AdvertTimer::new(ct); 5];
+
     Meow_Init(ct);
 
     for i in 1..=4 {
@@ -157,6 +656,98 @@ pub unsafe extern "C" fn _start() -> ! {
     loop {
         Meow_PerSec(cur_time(), &rules[0], rule_count as u64);
 
+        for port in 1..=4u8 {
+            match addr_checks[port as usize].poll(cur_time()) {
+                Some(iface::AddrAction::Probe) => {
+                    ARP::request(MACS[port as usize], [0; 4], [0; 6], IPS[port as usize])
+                        .send(&mut snd_handle, port, [255; 6]);
+                },
+                Some(iface::AddrAction::Announce) => send_announcement(&mut snd_handle, port),
+                None => {},
+            }
+
+            // Advertising an address still being checked may hand out a conflicting router
+            if IFACES[port as usize].router_ad && !addr_checks[port as usize].is_probing()
+                && advert_timers[port as usize].poll(cur_time())
+            {
+                send_router_ad(&mut snd_handle, port, ALL_SYSTEMS, ALL_SYSTEMS_MAC);
+            }
+        }
+
+        if next_stats < cur_time() {
+            ncache.hprint_counters();
+            icmp_limiter.hprint_counters();
+            hold.hprint_counters();
+            ip_drops.hprint();
+            next_stats += STATS_INTERVAL_US;
+        }
+
+        reasm.poll(cur_time(), |slot| {
+            // Only reported when the first fragment, which the error quotes, was received
+            if let Some(datagram) = slot.datagram() {
+                send_icmp_error(
+                    &mut snd_handle,
+                    &mut icmp_limiter,
+                    slot.port,
+                    slot.src,
+                    slot.link_broadcast,
+                    &datagram,
+                    ICMPType::TimeExceeded,
+                    ICMP_TIME_EXCEEDED_REASM,
+                    [0; 4],
+                );
+            }
+        });
+
+        hold.poll(cur_time(), |pkt| send_icmp_error(
+            &mut snd_handle,
+            &mut icmp_limiter,
+            pkt.port,
+            pkt.src,
+            pkt.link_broadcast,
+            &pkt.datagram(),
+            ICMPType::Unreachable,
+            ICMP_UNREACH_HOST,
+            [0; 4],
+        ));
+
+        ncache.poll(cur_time(), |ev| match ev {
+            nc::NCEvent::Request(ent) => {
+                let port = ent.port as usize;
+                ARP::request(MACS[port], IPS[port], [0; 6], ent.ip)
+                    .send(&mut snd_handle, ent.port, [255; 6]);
+            },
+            nc::NCEvent::Failed(ent) => {
+                hprint("NC failed: ");
+                hprint_ip(&ent.ip);
+                hprint("\n\r");
+
+                hold.release(&ent.ip, |pkt| send_icmp_error(
+                    &mut snd_handle,
+                    &mut icmp_limiter,
+                    pkt.port,
+                    pkt.src,
+                    pkt.link_broadcast,
+                    &pkt.datagram(),
+                    ICMPType::Unreachable,
+                    ICMP_UNREACH_HOST,
+                    [0; 4],
+                ));
+            },
+            nc::NCEvent::Probe(ent) => {
+                let port = ent.port as usize;
+                ARP::request(MACS[port], IPS[port], ent.mac, ent.ip)
+                    .send(&mut snd_handle, ent.port, ent.mac);
+            },
+            nc::NCEvent::Expired(ent) => {
+                hprint("NC expired: ");
+                hprint_ip(&ent.ip);
+                hprint("\n\r");
+
+                _cuckoo.forget_egress(&ent.mac);
+            },
+        });
+
         if let Some(timeout) = pending_refresh {
             if timeout < cur_time() {
                 hprint(">>>> Route applying: ");
@@ -165,6 +756,7 @@ pub unsafe extern "C" fn _start() -> ! {
 
                 routing_alloc.reset();
                 routing_table = Trie::from_rules(&mut routing_alloc, &mut rules[0..rule_count]);
+                pin_next_hops(&mut ncache, &rules[0..rule_count]);
 
                 hprint("Alloc: ");
                 hprint_dec(routing_alloc.ptr as u64);
@@ -187,14 +779,86 @@ pub unsafe extern "C" fn _start() -> ! {
 
         let probed = buf_handle.probe();
 
+        // Only IPv4 datagrams can miss the hardware tables, which are parsed as such
+        let missed = probed == BufState::ARPMiss || probed == BufState::ForwardMiss;
+        if missed && buf_handle.eth_type() != Some(EthType::IPv4) {
+            hprint("Miss on a non-IPv4 frame, dropping\n\r");
+            buf_handle.drop();
+            continue;
+        }
+
+        // Malformed datagrams never reach the handlers, whichever path they take
+        let received = probed == BufState::Incoming || missed;
+        if received && buf_handle.eth_type() == Some(EthType::IPv4) {
+            if let Err(reason) = Ipv4Packet::new_checked(buf_handle.payload()) {
+                hprint("Malformed IPv4 datagram, dropping\n\r");
+                ip_drops.count(reason);
+                buf_handle.drop();
+                continue;
+            }
+        }
+
         match probed {
             BufState::Incoming => {
                 match buf_handle.parse() {
-                    ParsedBufHandle::ARP(ptr) => {
-                        let mut arp = core::ptr::read_volatile(ptr);
+                    ParsedBufHandle::ARP(payload) => {
+                        let port = buf_handle.port();
+
+                        let pkt = match ArpPacket::new_checked(payload) {
+                            Some(pkt) => pkt,
+                            None => {
+                                hprint("Malformed ARP, ignoring\n\r");
+                                buf_handle.drop();
+                                continue;
+                            },
+                        };
+
+                        let mut arp = pkt.read();
+
+                        // Someone else using our address, or probing for it while we are
+                        let own = IPS[port as usize];
+                        let foreign = arp.sha != MACS[port as usize];
+                        if foreign && (arp.spa == own || (arp.spa == [0, 0, 0, 0] && arp.tpa == own && addr_checks[port as usize].is_probing())) {
+                            hprint("WARNING: address conflict on ");
+                            hprint_ip(&own);
+                            hprint(" with ");
+                            hprint_mac(&arp.sha);
+                            hprint(" @ ");
+                            hprint_dec(port as u64);
+                            hprint("\n\r");
+
+                            if addr_checks[port as usize].conflict(cur_time()) {
+                                send_announcement(&mut snd_handle, port);
+                            }
+                        }
+
+                        let proxied = arp.op == Oper::Req
+                            && arp.tpa != IPS[port as usize]
+                            && should_proxy_arp(&IFACES[port as usize], &IPS[port as usize], port, &arp, |ip| {
+                                unsafe { routing_table.as_ref() }.lookup_route(ip)
+                            });
+                        let for_us = arp.tpa == IPS[port as usize] || proxied;
+
+                        // RFC 826 merge: update known senders, only add new ones that talk to us
+                        if arp.spa != [0, 0, 0, 0] && arp.spa != own && (for_us || ncache.contains(&arp.spa)) {
+                            if ncache.lookup(&arp.spa).is_none() {
+                                hprint("ARP cache put:\n\r");
+                                hprint("  ");
+                                hprint_ip(&arp.spa);
+                                hprint(" -> ");
+                                hprint_mac(&arp.sha);
+                                hprint("\n\r");
+                            }
+
+                            if let Some(prev) = ncache.put(arp.spa, arp.sha, port, cur_time()) {
+                                _cuckoo.forget_egress(&prev.mac);
+                            }
+
+                            hold.release(&arp.spa, |pkt| send_held(&mut snd_handle, &mut icmp_limiter, pkt, arp.sha, port));
+                        }
+
                         match arp.op {
                             Oper::Reply => {
-                                let port = buf_handle.port();
                                 hprint("ARP reply: ");
                                 hprint_ip(&arp.spa);
                                 hprint(" @ ");
@@ -203,32 +867,25 @@ pub unsafe extern "C" fn _start() -> ! {
                                 hprint_dec(port as u64);
                                 hprint("\n\r");
 
-                                if ncache.lookup(&arp.spa).is_none() {
-                                    ncache.put(arp.spa, arp.sha, buf_handle.port());
-                                }
-
                                 buf_handle.drop();
                             },
-                            Oper::Req => {
-                                let port = buf_handle.port();
-
-                                if ncache.lookup(&arp.spa).is_none() {
-                                    hprint("ARP cache put:\n\r");
-                                    hprint("  ");
-                                    hprint_ip(&arp.spa);
-                                    hprint(" -> ");
-                                    hprint_mac(&arp.sha);
+                            Oper::Req if for_us => {
+                                if proxied {
+                                    hprint("Proxy ARP: ");
+                                    hprint_ip(&arp.tpa);
+                                    hprint(" @ ");
+                                    hprint_dec(port as u64);
                                     hprint("\n\r");
-                                    ncache.put(arp.spa, arp.sha, buf_handle.port());
                                 }
 
+                                let target = arp.tpa;
                                 arp.tpa = arp.spa;
                                 arp.tha = arp.sha;
-                                arp.spa = IPS[port as usize];
+                                arp.spa = target;
                                 arp.sha = MACS[port as usize];
                                 arp.op = Oper::Reply;
 
-                                core::ptr::write_volatile(ptr, arp);
+                                pkt.write(&arp);
 
                                 let src = buf_handle.src();
                                 buf_handle.write_dest(src);
@@ -236,89 +893,165 @@ pub unsafe extern "C" fn _start() -> ! {
 
                                 buf_handle.send();
                             },
+                            Oper::Req => {
+                                // Not asking for us
+                                buf_handle.drop();
+                            },
                         }
                     },
-                    ParsedBufHandle::IPv4(handle, body) => {
+                    ParsedBufHandle::IPv4(handle) => {
                         // hprint("IP:\n\r");
 
                         let proto = handle.proto();
 
-                        if proto == IPProto::ICMP {
-                            hprint("> ICMP\n\r");
-
-                            let tot_size = handle.payload_len();
-
-                            let mut icmp_rd = [0; 128];
-                            for i in 0..tot_size {
-                                unsafe {
-                                    icmp_rd[i as usize] = core::ptr::read_volatile(body.offset(i as isize));
-                                }
+                        // Routers have to look into IGMP messages flagged with a router alert
+                        let local = is_local(&handle.dest()) || (proto == IPProto::IGMP as u8 && handle.router_alert());
+
+                        // Local services only see whole datagrams
+                        let mut handle = if local && handle.is_fragment() {
+                            match reasm.add(&handle, buf_handle.port(), buf_handle.src(), buf_handle.link_broadcast(), cur_time()) {
+                                reasm::Reasm::Complete(whole) => whole,
+                                reasm::Reasm::Incomplete => {
+                                    buf_handle.drop();
+                                    continue;
+                                },
+                                reasm::Reasm::Dropped => {
+                                    hprint("> Fragment dropped\n\r");
+                                    buf_handle.drop();
+                                    continue;
+                                },
                             }
+                        } else {
+                            handle
+                        };
 
-                            let icmp: ICMPHeader::<60> = core::mem::transmute(icmp_rd);
-
-                            if icmp.r#type == ICMPType::EchoRequest {
-                                // Construct icmp reply
-                                let mut reply = ICMPHeader::<60> {
-                                    r#type: ICMPType::EchoReply,
-                                    code: 0,
-                                    rest: icmp.rest,
-                                    chksum: 0,
-                                    body: icmp.body,
-                                };
-
-                                // Fill in body
-                                // let tot_size = 64;
-                                reply.fill_chksum(tot_size);
-
-                                let mut ipbuf = [0u8;20];
-                                let (mut ip, _) = IPv4Handle::allocate(&mut ipbuf[0]);
-
-                                let port = buf_handle.port();
-                                snd_handle.write_dest(buf_handle.src());
-                                snd_handle.write_src(MACS[port as usize]);
-                                snd_handle.write_port(port);
-
-                                ip.outgoing(IPProto::ICMP, tot_size, IPS[port as usize], handle.src());
-
-                                let mut snd_data = snd_handle.data() as *mut u8;
-                                let snd_data_origin = snd_data;
-
-                                let ipbuf: [u8; 20] = core::mem::transmute(ipbuf);
-                                let reply: [u8; 128] = core::mem::transmute(reply);
-
-                                for i in ipbuf.iter() {
-                                    core::ptr::write_volatile(snd_data, *i);
-                                    snd_data = snd_data.offset(1);
-                                }
-
-                                for i in 0..tot_size {
-                                    core::ptr::write_volatile(snd_data, reply[i as usize]);
-                                    snd_data = snd_data.offset(1);
-                                }
-
-                                let payload_len = (snd_data as usize - snd_data_origin as usize) as u16;
+                        if local {
+                            handle.process_options(IPS[buf_handle.port() as usize], timestamp());
+                        }
 
-                                snd_handle.write_eth_type(EthType::IPv4);
-                                snd_handle.write_payload_len(payload_len);
-                                snd_handle.send();
+                        if !local {
+                            // Punted by the hardware instead of being forwarded
+                            if handle.ttl() <= 1 {
+                                hprint("> TTL exceeded\n\r");
+
+                                send_icmp_error(
+                                    &mut snd_handle,
+                                    &mut icmp_limiter,
+                                    buf_handle.port(),
+                                    buf_handle.src(),
+                                    buf_handle.link_broadcast(),
+                                    &handle.bytes(),
+                                    ICMPType::TimeExceeded,
+                                    ICMP_TIME_EXCEEDED_TTL,
+                                    [0; 4],
+                                );
+                                buf_handle.drop();
                             } else {
-                                hprint("> Unsupported ICMP type\n\r");
+                                let route = unsafe { routing_table.as_ref() }.lookup_route(&handle.dest());
+                                slow_forward(&mut buf_handle, &mut snd_handle, &mut icmp_limiter, &mut ncache, &mut hold, route);
+                            }
+                        } else if proto == IPProto::ICMP as u8 {
+                            hprint("> ICMP\n\r");
+
+                            let port = buf_handle.port();
+                            let tot_size = handle.payload_len() as usize;
+
+                            match handle.icmp() {
+                                None => hprint("> Truncated ICMP, dropping\n\r"),
+                                Some(_) if handle.header_len() as usize + tot_size > reasm::REASM_DATAGRAM_MAX => {
+                                    hprint("> Oversized ICMP, dropping\n\r");
+                                },
+                                Some(icmp) if !icmp.verify() => hprint("> Bad ICMP checksum, dropping\n\r"),
+                                Some(icmp) => if icmp.r#type() == ICMPType::EchoRequest as u8 {
+                                    send_icmp_reply(
+                                        &mut snd_handle,
+                                        &mut icmp_limiter,
+                                        port,
+                                        handle.src(),
+                                        buf_handle.src(),
+                                        &handle,
+                                        &icmp,
+                                        tot_size as u16,
+                                        ICMPType::EchoReply,
+                                        None,
+                                    );
+                                } else if icmp.r#type() == ICMPType::Timestamp as u8 {
+                                    let received = timestamp();
+
+                                    if tot_size != TIMESTAMP_LEN {
+                                        hprint("> Malformed timestamp request, dropping\n\r");
+                                    } else {
+                                        send_icmp_reply(
+                                            &mut snd_handle,
+                                            &mut icmp_limiter,
+                                            port,
+                                            handle.src(),
+                                            buf_handle.src(),
+                                            &handle,
+                                            &icmp,
+                                            TIMESTAMP_LEN as u16,
+                                            ICMPType::TimestampReply,
+                                            Some(received),
+                                        );
+                                    }
+                                } else if icmp.r#type() == ICMPType::RouterSol as u8 {
+                                    if !IFACES[port as usize].router_ad {
+                                        hprint("> Router discovery disabled, ignoring\n\r");
+                                    } else if handle.src() == [0, 0, 0, 0] {
+                                        advert_timers[port as usize].solicited(cur_time());
+                                    } else {
+                                        send_router_ad(&mut snd_handle, port, handle.src(), buf_handle.src());
+                                    }
+                                } else if icmp.r#type() == ICMPType::InfoRequest as u8 {
+                                    // Only the identifier and sequence number are meaningful.
+                                    // Requests from hosts not knowing their network are answered fully addressed.
+                                    let dst = IFACES[port as usize].fill_network(&IPS[port as usize], &handle.src());
+                                    send_icmp_reply(
+                                        &mut snd_handle,
+                                        &mut icmp_limiter,
+                                        port,
+                                        dst,
+                                        buf_handle.src(),
+                                        &handle,
+                                        &icmp,
+                                        ICMP_HEADER_LEN as u16,
+                                        ICMPType::InfoReply,
+                                        None,
+                                    );
+                                } else {
+                                    hprint("> Unsupported ICMP type\n\r");
+                                },
                             }
                             buf_handle.drop();
-                        } else if proto == IPProto::IGMP {
-                            hprint("> IGMP, ignoring\n\r");
+                        } else if proto == IPProto::IGMP as u8 {
+                            if handle.router_alert() {
+                                hprint("> IGMP with router alert, ignoring\n\r");
+                            } else {
+                                hprint("> IGMP, ignoring\n\r");
+                            }
                             buf_handle.drop();
-                        } else if proto == IPProto::TCP {
+                        } else if proto == IPProto::TCP as u8 {
                             hprint("> TCP, ignoring\n\r");
                             buf_handle.drop();
-                        } else if proto == IPProto::UDP {
+                        } else if proto == IPProto::UDP as u8 {
                             // hprint("> UDP\n\r");
+                            let udp = match handle.udp() {
+                                Some(udp) if udp.verify(&handle.src(), &handle.dest()) => udp,
+                                _ => {
+                                    hprint("> Malformed UDP, dropping\n\r");
+                                    buf_handle.drop();
+                                    continue;
+                                },
+                            };
+
                             rule_updated = false;
 
-                            Meow_ReceiveIPPacket(
-                                buf_handle.data(),
-                                buf_handle.payload_len() as usize,
+                            // Addresses are in memory order, as in RIP entries
+                            let payload = udp.payload();
+                            Meow_ReceiveUDPPacket(
+                                payload.as_ptr(),
+                                payload.len(),
+                                u32::from_ne_bytes(handle.src()),
                                 &buf_handle.src(),
                                 buf_handle.port() - 1,
                                 &rules[0],
@@ -353,53 +1086,17 @@ pub unsafe extern "C" fn _start() -> ! {
             },
             BufState::ARPMiss => {
                 hprint("ARP miss packet\n\r");
-                let ptr = buf_handle.data();
-                let dest = unsafe { core::ptr::read(ptr.offset(16) as *const [u8; 4]) };
-
-                if let Some(next_hop) = unsafe { routing_table.as_ref() }.lookup(&dest) {
-                    if let Some(idx) = ncache.lookup(&next_hop) {
-                        ncache.write_hardware(idx);
-                        let result = ncache.get(idx);
-                        buf_handle.write_dest(result.mac);
-                        buf_handle.write_port(result.port);
-                        buf_handle.send();
-                    } else {
-                        for i in 1..=4 {
-                            let arp = ARP {
-                                htype: HType::Eth,
-                                ptype: EthType::IPv4,
-                                hlen: 6,
-                                plen: 4,
-                                op: Oper::Req,
-                                sha: MACS[i],
-                                spa: IPS[i],
-                                tha: [0,0,0,0,0,0],
-                                tpa: next_hop,
-                            };
-
-                            let mut snd_data = snd_handle.data() as *mut u8;
-                            let snd_data_origin = snd_data;
-
-                            let buf: [u8; core::mem::size_of::<ARP>()] = core::mem::transmute(arp);
-
-                            for i in buf.iter() {
-                                core::ptr::write_volatile(snd_data, *i);
-                                snd_data = snd_data.offset(1);
-                            }
-
-                            let payload_len = (snd_data as usize - snd_data_origin as usize) as u16;
-
-                            snd_handle.write_src(MACS[i]);
-                            snd_handle.write_dest([255,255,255,255,255,255]);
-
-                            snd_handle.write_port(i as u8);
-                            snd_handle.write_eth_type(EthType::ARP);
-                            snd_handle.write_payload_len(payload_len);
-                            snd_handle.send();
-                        }
+                let dest = Ipv4Packet::new_unchecked(buf_handle.payload()).dest();
 
-                        buf_handle.drop();
+                // Fast path: the egress was resolved when the flow was cached
+                if let Some((next_hop, Some(egress))) = _cuckoo.lookup_egress(&hw_order(&dest)) {
+                    if let Some(idx) = ncache.lookup(&hw_order(&next_hop)) {
+                        let _ = ncache.write_hardware(idx, cur_time());
                     }
+
+                    forward(&mut buf_handle, &mut snd_handle, &mut icmp_limiter, egress.mac, egress.port);
+                } else if let Some(route) = unsafe { routing_table.as_ref() }.lookup_route(&dest) {
+                    send_to_next_hop(&mut buf_handle, &mut snd_handle, &mut icmp_limiter, &mut ncache, &mut hold, &dest, &route);
                 } else {
                     // Ignores race
                 }
@@ -407,44 +1104,53 @@ pub unsafe extern "C" fn _start() -> ! {
             BufState::ForwardMiss => {
                 hprint("Forward miss packet\n\r");
 
-                // Asserts to be IP
-                let ptr = buf_handle.data();
-                let dest = unsafe { core::ptr::read(ptr.offset(16) as *const [u8; 4]) };
-
-                match unsafe { routing_table.as_ref() }.lookup(&dest) {
-                    Some(rule) => {
-                        hprint("Found rule: ");
-                        hprint_ip(&dest);
-                        hprint(" -> ");
-                        hprint_ip(&rule);
-                        hprint("\n\r");
-
-                        unsafe {
-                            if let Err(_) = _cuckoo.insert(&[
-                                dest[3],
-                                dest[2],
-                                dest[1],
-                                dest[0],
-                            ], &[
-                                rule[3],
-                                rule[2],
-                                rule[1],
-                                rule[0],
-                            ], true) {
-                                hprint("Cuckoo write failed.");
-                            }
-                        }
-                    },
-                    None => {
-                        hprint("Error! Routing failed: ");
-                        hprint_ip(&dest);
-                        hprint(" -> !\n\r");
+                // Checked to be IPv4 above
+                let ip = Ipv4Packet::new_unchecked(buf_handle.payload());
+                let dest = ip.dest();
+
+                if ip.ttl() <= 1 {
+                    send_icmp_error(
+                        &mut snd_handle,
+                        &mut icmp_limiter,
+                        buf_handle.port(),
+                        buf_handle.src(),
+                        buf_handle.link_broadcast(),
+                        &ip.bytes(),
+                        ICMPType::TimeExceeded,
+                        ICMP_TIME_EXCEEDED_TTL,
+                        [0; 4],
+                    );
+                    buf_handle.drop();
+                    continue;
+                }
 
-                        panic!("Halt");
-                    },
+                let route = unsafe { routing_table.as_ref() }.lookup_route(&dest);
+                if let Some(route) = route {
+                    let rule = route.next;
+                    hprint("Found rule: ");
+                    hprint_ip(&dest);
+                    hprint(" -> ");
+                    hprint_ip(&rule);
+                    hprint("\n\r");
+
+                    // Resolve the egress now if the neighboor is known,
+                    // so that the flow does not come back as an ARP miss
+                    let egress = ncache.lookup(&rule).map(|idx| {
+                        let _ = ncache.write_hardware(idx, cur_time());
+
+                        let result = ncache.get(idx);
+                        Egress::new(result.mac, result.port)
+                    });
+
+                    unsafe {
+                        if let Err(_) = _cuckoo.insert_with_egress(&hw_order(&dest), &hw_order(&rule), egress, true) {
+                            hprint("Cuckoo write failed.");
+                        }
+                    }
                 }
 
-                buf_handle.drop();
+                // The packet itself goes through software, later ones hit the cache
+                slow_forward(&mut buf_handle, &mut snd_handle, &mut icmp_limiter, &mut ncache, &mut hold, route);
             }
         }
     }
@@ -525,19 +1231,123 @@ pub unsafe extern "C" fn Meow_Update(insert: bool, r: *const routing::Rule) -> b
 
 #[no_mangle]
 pub unsafe extern "C" fn Meow_ArpGetMacAddress(if_index: u8, ip: u32, ret: &mut [u8; 6]) -> usize {
-    match (&*ncache_ptr).lookup(core::mem::transmute(&ip)) {
-        Some(idx) => {
-            let result = (&*ncache_ptr).get(idx);
+    let ip: [u8; 4] = core::mem::transmute(ip);
+    let port = if_index + 1;
+
+    let ncache = &mut *ncache_ptr;
+
+    match ncache.lookup(&ip) {
+        Some(idx) if ncache.get(idx).port == port => {
+            let result = ncache.get(idx);
             *ret = result.mac;
             0
         },
-        None => {
+        _ => {
+            if let nc::Resolve::Request(evicted) = ncache.resolve(ip, port, cur_time()) {
+                if let Some(evicted) = evicted {
+                    _cuckoo.forget_egress(&evicted.mac);
+                }
+
+                ARP::request(MACS[port as usize], IPS[port as usize], [0; 6], ip)
+                    .send(&mut buf::snd_buf(), port, [255; 6]);
+            }
             1
         }
     }
 }
 
 
+/// Neighboor cache entry, as seen by the management channels
+#[repr(C)]
+pub struct ArpEntryInfo {
+    pub ip: [u8; 4],
+    pub mac: [u8; 6],
+    pub if_index: u8,
+    /// ARP_INFO_* bits
+    pub flags: u8,
+}
+
+pub const ARP_INFO_RESOLVED: u8 = 1 << 0;
+pub const ARP_INFO_STATIC: u8 = 1 << 1;
+pub const ARP_INFO_HARDWARE: u8 = 1 << 2;
+
+/// Writes at most max entries of the neighboor cache into out, returns the number written
+#[no_mangle]
+pub unsafe extern "C" fn Meow_ArpList(out: *mut ArpEntryInfo, max: usize) -> usize {
+    let mut cnt = 0;
+
+    for ent in (&*ncache_ptr).iter().take(max) {
+        let mut flags = 0;
+        if ent.is_resolved() { flags |= ARP_INFO_RESOLVED; }
+        if ent.is_static() { flags |= ARP_INFO_STATIC; }
+        if ent.in_hardware() { flags |= ARP_INFO_HARDWARE; }
+
+        *out.offset(cnt as isize) = ArpEntryInfo {
+            ip: ent.ip,
+            mac: ent.mac,
+            if_index: ent.port.wrapping_sub(1),
+            flags,
+        };
+        cnt += 1;
+    }
+
+    cnt
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Meow_ArpAdd(ip: u32, mac: *const [u8; 6], if_index: u8, pin: bool) -> usize {
+    let ip: [u8; 4] = core::mem::transmute(ip);
+    let ncache = &mut *ncache_ptr;
+
+    // The previous entry of ip is replaced in place, and kept if the new one cannot be added
+    match ncache.add_static(ip, *mac, if_index + 1, pin, cur_time()) {
+        Ok(prev) => {
+            if let Some(prev) = prev {
+                _cuckoo.forget_egress(&prev.mac);
+            }
+            0
+        },
+        Err(()) => 1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Meow_ArpDelete(ip: u32) -> usize {
+    let ip: [u8; 4] = core::mem::transmute(ip);
+
+    match (&mut *ncache_ptr).remove(&ip) {
+        Ok(prev) => {
+            _cuckoo.forget_egress(&prev);
+            0
+        },
+        Err(()) => 1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn Meow_ArpFlush(include_static: bool) {
+    (&mut *ncache_ptr).flush(include_static);
+    _cuckoo.forget_all_egress();
+}
+
+/// Aligns the clock used in ICMP timestamps, given the current Unix time in ms
+#[no_mangle]
+pub unsafe extern "C" fn Meow_SetWallTime(unix_ms: u64) {
+    set_wall_time(unix_ms * 1000);
+}
+
+/// Internet checksum of len bytes at data, in host byte order
+#[no_mangle]
+pub unsafe extern "C" fn Meow_Checksum(data: *const u8, len: usize) -> u16 {
+    checksum::checksum(data, len)
+}
+
+/// Dumps the neighboor cache onto the console
+#[no_mangle]
+pub unsafe extern "C" fn Meow_ArpShow() {
+    (&*ncache_ptr).hprint_table();
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn Meow_SendIPPacket(buffer: *const u8, length: usize, if_index: u8, dst_mac: *const [u8; 6]) -> usize {
     /*
@@ -549,9 +1359,7 @@ pub unsafe extern "C" fn Meow_SendIPPacket(buffer: *const u8, length: usize, if_
     // Write directly into snd_buf
     let mut buf = buf::snd_buf();
 
-    let ptr = buf.data();
-
-    core::ptr::copy_nonoverlapping(buffer, ptr, length);
+    buf.room().copy_from(0, &Bytes::new(buffer as *mut u8, length));
     buf.write_eth_type(EthType::IPv4);
     buf.write_payload_len(length as u16);
     buf.write_src(MACS[if_index as usize]);
@@ -562,9 +1370,102 @@ pub unsafe extern "C" fn Meow_SendIPPacket(buffer: *const u8, length: usize, if_
     0
 }
 
+/**
+ * Sends length bytes at payload as a UDP datagram from our address on if_index to dst,
+ * through the neighboor dst_mac, with the given TTL and type of service.
+ * dst is in memory order, as are the addresses of RIP entries.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn Meow_SendUDPPacket(
+    payload: *const u8,
+    length: usize,
+    src_port: u16,
+    dst_port: u16,
+    dst: u32,
+    ttl: u8,
+    tos: u8,
+    if_index: u8,
+    dst_mac: *const [u8; 6],
+) -> usize {
+    let dst: [u8; 4] = core::mem::transmute(dst);
+    let port = if_index + 1;
+
+    let mut buf = buf::snd_buf();
+    let len = PacketBuilder::new(&buf)
+        .ethernet(MACS[port as usize], *dst_mac, port)
+        .ipv4(IPS[port as usize], dst)
+        .ttl(ttl)
+        .tos(tos)
+        .udp(src_port, dst_port)
+        .payload_from(&Bytes::new(payload as *mut u8, length))
+        .finish();
+
+    buf.write_payload_len(len);
+    buf.send();
+
+    0
+}
+
 extern "C" {
-    fn Meow_ReceiveIPPacket(packet: *const u8, length: usize, src_mac: &[u8; 6], if_index: u8, tbl: *const Rule, count: u64) -> u64;
+    fn Meow_ReceiveUDPPacket(payload: *const u8, length: usize, src: u32, src_mac: &[u8; 6], if_index: u8, tbl: *const Rule, count: u64) -> u64;
     fn Meow_Init(usec: u64) -> u64;
     fn Meow_PerSec(usec: u64, tbl: *const Rule, count: u64) -> u64;
     fn Meow_AddInterface(addr: u32);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: IPAddr = [192, 168, 0, 1];
+    const PORT: u8 = 1;
+
+    fn route(next: IPAddr, port: u8) -> Route {
+        Route { next, if_index: port - 1 }
+    }
+
+    #[test]
+    fn test_should_proxy_arp() {
+        let iface = iface::IfaceConfig { proxy_arp: true, ..iface::IfaceConfig::DEFAULT };
+        let proxy = |iface: &iface::IfaceConfig, spa: IPAddr, tpa: IPAddr, route: Option<Route>| {
+            should_proxy_arp(iface, &ADDR, PORT, &ARP::request([2, 0, 0, 0, 0, 1], spa, [0; 6], tpa), |_| route)
+        };
+
+        let host = [192, 168, 0, 10];
+        let remote = [192, 168, 1, 10];
+        assert!(proxy(&iface, host, remote, Some(route([192, 168, 1, 2], 2))));
+        assert!(!proxy(&iface::IfaceConfig::DEFAULT, host, remote, Some(route([192, 168, 1, 2], 2))));
+
+        // Requesters on another subnet, probes and targets on the requester's subnet
+        assert!(!proxy(&iface, [10, 0, 0, 10], remote, Some(route([192, 168, 1, 2], 2))));
+        assert!(!proxy(&iface, [0, 0, 0, 0], remote, Some(route([192, 168, 1, 2], 2))));
+        assert!(!proxy(&iface, host, [192, 168, 0, 20], Some(route([192, 168, 0, 2], PORT))));
+
+        // Targets reached through the same port, ignored or without a route
+        assert!(!proxy(&iface, host, remote, Some(route([192, 168, 0, 2], PORT))));
+        assert!(!proxy(&iface, host, remote, Some(route([255, 255, 255, 255], 2))));
+        assert!(!proxy(&iface, host, remote, None));
+    }
+
+    #[test]
+    fn test_should_redirect() {
+        let iface = iface::IfaceConfig::DEFAULT;
+        let host = [192, 168, 0, 10];
+        let gateway = [192, 168, 0, 2];
+
+        // Forwarded back out the ingress port, to another router on the subnet of the source
+        assert!(should_redirect(&iface, &ADDR, PORT, &host, &route(gateway, PORT)));
+
+        let quiet = iface::IfaceConfig { send_redirects: false, ..iface };
+        assert!(!should_redirect(&quiet, &ADDR, PORT, &host, &route(gateway, PORT)));
+
+        // Routes through a different port, ignored routes, and sources that are already the first hop
+        assert!(!should_redirect(&iface, &ADDR, PORT, &host, &route([192, 168, 1, 2], 2)));
+        assert!(!should_redirect(&iface, &ADDR, PORT, &host, &route([255, 255, 255, 255], PORT)));
+        assert!(!should_redirect(&iface, &ADDR, PORT, &gateway, &route(gateway, PORT)));
+
+        // Sources and next hops off the subnet of the ingress port
+        assert!(!should_redirect(&iface, &ADDR, PORT, &[10, 1, 0, 10], &route(gateway, PORT)));
+        assert!(!should_redirect(&iface, &ADDR, PORT, &host, &route([10, 1, 0, 2], PORT)));
+    }
+}
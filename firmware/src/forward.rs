@@ -3,6 +3,41 @@ use super::routing::IPAddr;
 const ROWS_NUM: u32 = 1024;
 const ROWS_NUM_MASK: u32 = ROWS_NUM - 1;
 
+/// Resolved egress of a cached next hop.
+/// The hardware never reads these, they only spare the firmware a neighbour cache walk.
+/// An all-zero entry (port 0) means no egress is cached.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Egress {
+    pub mac: [u8; 6],
+    pub port: u8,
+    _pad: u8,
+}
+
+impl Egress {
+    const NONE: Egress = Egress {
+        mac: [0; 6],
+        port: 0,
+        _pad: 0,
+    };
+
+    pub fn new(mac: [u8; 6], port: u8) -> Self {
+        assert_ne!(port, 0);
+        Egress {
+            mac, port,
+            _pad: 0,
+        }
+    }
+
+    fn get(&self) -> Option<Egress> {
+        if self.port == 0 {
+            None
+        } else {
+            Some(*self)
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 struct Row {
@@ -28,24 +63,34 @@ impl Row {
         None
     }
 
-    fn insert(&mut self, k: &IPAddr, v: &IPAddr) -> Result<(), ()> {
+    fn find(&self, k: &IPAddr) -> Option<usize> {
+        for i in 0..self.keys.len() {
+            if &self.keys[i] == k {
+                return Some(i);
+            }
+        }
+
+        None
+    }
+
+    fn insert(&mut self, k: &IPAddr, v: &IPAddr) -> Result<usize, ()> {
         assert_ne!(k, &[0, 0, 0, 0]);
         for i in 0..self.keys.len() {
             if self.keys[i] == [0, 0, 0, 0] {
                 self.keys[i] = *k;
                 self.values[i] = *v;
-                return Ok(());
+                return Ok(i);
             }
         }
         
         Err(())
     }
 
-    fn modify(&mut self, k: &IPAddr, v: &IPAddr) -> Result<(), ()> {
+    fn modify(&mut self, k: &IPAddr, v: &IPAddr) -> Result<usize, ()> {
         for i in 0..self.keys.len() {
             if &self.keys[i] == k {
                 self.values[i] =*v;
-                return Ok(());
+                return Ok(i);
             }
         }
 
@@ -78,6 +123,8 @@ fn test_row() -> Result<(), ()> {
 #[repr(C)]
 pub struct Cuckoo {
     rows: [Row; ROWS_NUM as usize],
+    // Software-only, placed after the rows walked by the hardware
+    egress: [[Egress; 4]; ROWS_NUM as usize],
 }
 
 // used for cuckoo to decide which key should be kicked out
@@ -90,6 +137,7 @@ impl Cuckoo {
 
         Cuckoo {
             rows: [Row::new(); ROWS_NUM as usize],
+            egress: [[Egress::NONE; 4]; ROWS_NUM as usize],
         }
     }
 
@@ -105,23 +153,35 @@ impl Cuckoo {
     /// The k cannot be zero ([0, 0, 0, 0]) since Cuckoo uses the zero as the invalid key internally.
     /// If setting the random_evict to true, then it will evict a random key to when there are not empty slots.
     pub fn insert(&mut self, k: &IPAddr, v: &IPAddr, random_evict: bool) -> Result<(), ()> {
+        self.insert_with_egress(k, v, None, random_evict)
+    }
+
+    /// Same as insert, but also caches the resolved egress of the next hop v.
+    /// Any previously cached egress of k is replaced.
+    pub fn insert_with_egress(&mut self, k: &IPAddr, v: &IPAddr, egress: Option<Egress>, random_evict: bool) -> Result<(), ()> {
+        let (rid, slot_id) = self.place(k, v, random_evict)?;
+        self.egress[rid][slot_id] = egress.unwrap_or(Egress::NONE);
+        Ok(())
+    }
+
+    fn place(&mut self, k: &IPAddr, v: &IPAddr, random_evict: bool) -> Result<(usize, usize), ()> {
         assert_ne!(k, &[0, 0, 0, 0]);
         let (row_id1, row_id2) = Cuckoo::row_ids(k);
 
         // try to modify it in cases it's already there
-        if self.rows[row_id1].modify(k, v) == Ok(()) {
-            return Ok(());
+        if let Ok(slot_id) = self.rows[row_id1].modify(k, v) {
+            return Ok((row_id1, slot_id));
         }
-        if self.rows[row_id2].modify(k, v) == Ok(()) {
-            return Ok(());
+        if let Ok(slot_id) = self.rows[row_id2].modify(k, v) {
+            return Ok((row_id2, slot_id));
         }
 
         // try to insert into the row_id1 first. If it is failed, try to insert into the row_id2
-        if self.rows[row_id1].insert(k, v) == Ok(()) {
-            return Ok(());
+        if let Ok(slot_id) = self.rows[row_id1].insert(k, v) {
+            return Ok((row_id1, slot_id));
         }
-        if self.rows[row_id2].insert(k, v) == Ok(()) {
-            return Ok(());
+        if let Ok(slot_id) = self.rows[row_id2].insert(k, v) {
+            return Ok((row_id2, slot_id));
         }
 
         // shift keys to other rows to reserve a slot for current key
@@ -129,7 +189,7 @@ impl Cuckoo {
             if let Some(slot_id) = self.shift(rid, 3) {
                 self.rows[rid].keys[slot_id] = *k;
                 self.rows[rid].values[slot_id] = *v;
-                return Ok(());
+                return Ok((rid, slot_id));
             }
         }
 
@@ -149,7 +209,7 @@ impl Cuckoo {
 
             self.rows[rid].keys[slot_id] = *k;
             self.rows[rid].values[slot_id] = *v;
-            return Ok(());
+            return Ok((rid, slot_id));
         }
 
         Err(())
@@ -185,6 +245,7 @@ impl Cuckoo {
                 // move the currnt key to new row, and return the empty slot
                 self.rows[row_id2].keys[slot_id] = self.rows[row_id].keys[i];
                 self.rows[row_id2].values[slot_id] = self.rows[row_id].values[i];
+                self.egress[row_id2][slot_id] = self.egress[row_id][i];
                 return Some(i);
             }
         }
@@ -199,6 +260,43 @@ impl Cuckoo {
             .or(self.rows[row_id2].lookup(k))
     }
 
+    /// Looks up k, returning its next hop together with the cached egress, if any.
+    pub fn lookup_egress(&self, k: &IPAddr) -> Option<(IPAddr, Option<Egress>)> {
+        let (rid, slot_id) = self.find(k)?;
+        Some((self.rows[rid].values[slot_id], self.egress[rid][slot_id].get()))
+    }
+
+    /// Caches the egress for an existing key, leaving its next hop untouched.
+    pub fn set_egress(&mut self, k: &IPAddr, egress: Egress) -> Result<(), ()> {
+        let (rid, slot_id) = self.find(k).ok_or(())?;
+        self.egress[rid][slot_id] = egress;
+        Ok(())
+    }
+
+    /// Drops every cached egress pointing to mac, e.g. when the neighboor has changed its address
+    pub fn forget_egress(&mut self, mac: &[u8; 6]) {
+        for row in self.egress.iter_mut() {
+            for egress in row.iter_mut() {
+                if egress.port != 0 && egress.mac == *mac {
+                    *egress = Egress::NONE;
+                }
+            }
+        }
+    }
+
+    pub fn forget_all_egress(&mut self) {
+        for row in self.egress.iter_mut() {
+            *row = [Egress::NONE; 4];
+        }
+    }
+
+    fn find(&self, k: &IPAddr) -> Option<(usize, usize)> {
+        let (row_id1, row_id2) = Cuckoo::row_ids(k);
+
+        self.rows[row_id1].find(k).map(|slot_id| (row_id1, slot_id))
+            .or(self.rows[row_id2].find(k).map(|slot_id| (row_id2, slot_id)))
+    }
+
     pub fn remove(&mut self, k: &IPAddr) -> Result<(), ()> {
         let (row_id1, row_id2) = Cuckoo::row_ids(k);
         
@@ -265,6 +363,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_cuckoo_egress() -> Result<(), ()> {
+        let mut c = Cuckoo::new();
+        let egress = Egress::new([0x9c, 0xeb, 0, 0, 0, 2], 2);
+
+        c.insert(&[192, 168, 1, 23], &[192, 168, 1, 1], false)?;
+        assert_eq!(c.lookup_egress(&[192, 168, 1, 23]), Some(([192, 168, 1, 1], None)));
+
+        c.set_egress(&[192, 168, 1, 23], egress)?;
+        assert_eq!(c.lookup_egress(&[192, 168, 1, 23]), Some(([192, 168, 1, 1], Some(egress))));
+        assert_eq!(c.set_egress(&[192, 168, 1, 24], egress), Err(()));
+
+        // Changing the next hop drops the stale egress
+        c.insert(&[192, 168, 1, 23], &[10, 1, 1, 1], false)?;
+        assert_eq!(c.lookup_egress(&[192, 168, 1, 23]), Some(([10, 1, 1, 1], None)));
+
+        c.insert_with_egress(&[192, 168, 1, 23], &[192, 168, 1, 1], Some(egress), false)?;
+        assert_eq!(c.lookup(&[192, 168, 1, 23]).unwrap(), [192, 168, 1, 1]);
+        assert_eq!(c.lookup_egress(&[192, 168, 1, 23]), Some(([192, 168, 1, 1], Some(egress))));
+
+        Ok(())
+    }
+
     fn gen_ipaddr() -> IPAddr {
         let mut rng = rand::thread_rng();
         [rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>()]
@@ -298,6 +419,10 @@ mod tests {
             assert_eq!(c.lookup(from).unwrap(), *to);
         }
 
+        for (from, to) in &kvs {
+            c.set_egress(from, Egress::new([to[0], to[1], to[2], to[3], 0, 0], 1))?;
+        }
+
         // check if keep inserting, will the random_evict works
         for _ in 0..key_cnt {
             loop {
@@ -317,6 +442,14 @@ mod tests {
             c.lookup(&k).is_some() as usize
         }).sum();
         assert!(keys_in_cuckoo > key_cnt + 10);
+
+        // egress entries follow their keys when shifted
+        for (from, to) in &kvs[0..key_cnt] {
+            if let Some((next, egress)) = c.lookup_egress(from) {
+                assert_eq!(next, *to);
+                assert_eq!(egress.unwrap().mac, [to[0], to[1], to[2], to[3], 0, 0]);
+            }
+        }
     
         Ok(())
     }
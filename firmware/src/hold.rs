@@ -0,0 +1,259 @@
+/*
+ * Packets held while their next hop is being resolved
+ *
+ */
+
+use crate::packet::Bytes;
+use crate::routing::IPAddr;
+use crate::util::*;
+
+const HOLD_POOL_SIZE: usize = 32;
+const HOLD_PER_NEXT_HOP: usize = 4;
+/// Largest IP packet that can be held, matching the Ethernet MTU
+const HOLD_PACKET_MAX: usize = 1500;
+
+/// Packets are dropped after this long even if the resolution did not conclude,
+/// e.g. because its neighboor cache entry was replaced
+const HOLD_TIMEOUT_US: u64 = 10_000_000;
+
+/// Why a packet could not be held
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum HoldDrop {
+    /// Larger than HOLD_PACKET_MAX
+    TooLarge,
+    /// HOLD_PER_NEXT_HOP packets already wait for the same next hop
+    NextHopFull,
+    PoolFull,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct HoldCounters {
+    pub held: u64,
+    /// Not sent before HOLD_TIMEOUT_US
+    pub expired: u64,
+    pub too_large: u64,
+    pub next_hop_full: u64,
+    pub pool_full: u64,
+}
+
+pub struct HeldPacket {
+    /// Port and source MAC the packet arrived from
+    pub port: u8,
+    pub src: [u8; 6],
+    /// If it arrived in a broadcast or multicast frame
+    pub link_broadcast: bool,
+    pub len: u16,
+    pub data: [u8; HOLD_PACKET_MAX],
+    next_hop: IPAddr,
+    since: u64,
+    valid: bool,
+}
+
+impl HeldPacket {
+    const EMPTY: HeldPacket = HeldPacket {
+        port: 0,
+        src: [0; 6],
+        link_broadcast: false,
+        len: 0,
+        data: [0; HOLD_PACKET_MAX],
+        next_hop: [0; 4],
+        since: 0,
+        valid: false,
+    };
+
+    /// The held IP packet
+    pub fn datagram(&self) -> Bytes {
+        Bytes::new(self.data.as_ptr() as *mut u8, self.len as usize)
+    }
+}
+
+pub struct HoldQueue {
+    pool: [HeldPacket; HOLD_POOL_SIZE],
+    counters: HoldCounters,
+}
+
+impl HoldQueue {
+    pub fn new() -> Self {
+        Self {
+            pool: [HeldPacket::EMPTY; HOLD_POOL_SIZE],
+            counters: HoldCounters::default(),
+        }
+    }
+
+    /**
+     * Copies the IP packet in datagram, received on port from src, into the pool,
+     * to be sent once next_hop is resolved.
+     *
+     * Fails if there is no room left, in which case the packet should be dropped.
+     * Either way, the outcome is counted.
+     */
+    pub fn hold(&mut self, next_hop: IPAddr, datagram: &Bytes, port: u8, src: [u8; 6], link_broadcast: bool, now: u64) -> Result<(), HoldDrop> {
+        let result = self.try_hold(next_hop, datagram, port, src, link_broadcast, now);
+
+        let cnt = &mut self.counters;
+        match result {
+            Ok(()) => cnt.held += 1,
+            Err(HoldDrop::TooLarge) => cnt.too_large += 1,
+            Err(HoldDrop::NextHopFull) => cnt.next_hop_full += 1,
+            Err(HoldDrop::PoolFull) => cnt.pool_full += 1,
+        }
+
+        result
+    }
+
+    fn try_hold(&mut self, next_hop: IPAddr, datagram: &Bytes, port: u8, src: [u8; 6], link_broadcast: bool, now: u64) -> Result<(), HoldDrop> {
+        let len = datagram.len();
+        if len > HOLD_PACKET_MAX {
+            return Err(HoldDrop::TooLarge);
+        }
+
+        let held = self.pool.iter().filter(|pkt| pkt.valid && pkt.next_hop == next_hop).count();
+        if held >= HOLD_PER_NEXT_HOP {
+            return Err(HoldDrop::NextHopFull);
+        }
+
+        let pkt = self.pool.iter_mut().find(|pkt| !pkt.valid).ok_or(HoldDrop::PoolFull)?;
+        pkt.port = port;
+        pkt.src = src;
+        pkt.link_broadcast = link_broadcast;
+        pkt.len = len as u16;
+        pkt.next_hop = next_hop;
+        pkt.since = now;
+        pkt.valid = true;
+
+        for i in 0..len {
+            pkt.data[i] = datagram.u8_at(i);
+        }
+
+        Ok(())
+    }
+
+    pub fn hprint_counters(&self) {
+        let cnt = &self.counters;
+        hprint("Hold: held ");
+        hprint_dec(cnt.held);
+        hprint(", expired ");
+        hprint_dec(cnt.expired);
+        hprint(", too large ");
+        hprint_dec(cnt.too_large);
+        hprint(", next hop full ");
+        hprint_dec(cnt.next_hop_full);
+        hprint(", pool full ");
+        hprint_dec(cnt.pool_full);
+        hprint("\n\r");
+    }
+
+    /**
+     * Hands the packets waiting for next_hop to cb, oldest first, and frees them.
+     * Called either when next_hop is resolved, or when its resolution failed.
+     */
+    pub fn release<F: FnMut(&HeldPacket)>(&mut self, next_hop: &IPAddr, mut cb: F) {
+        loop {
+            let oldest = self.pool.iter_mut()
+                .filter(|pkt| pkt.valid && pkt.next_hop == *next_hop)
+                .min_by_key(|pkt| pkt.since);
+
+            match oldest {
+                Some(pkt) => {
+                    cb(pkt);
+                    pkt.valid = false;
+                },
+                None => break,
+            }
+        }
+    }
+
+    /// Drops packets held for longer than HOLD_TIMEOUT_US, handing them to cb
+    pub fn poll<F: FnMut(&HeldPacket)>(&mut self, now: u64, mut cb: F) {
+        for pkt in self.pool.iter_mut() {
+            if pkt.valid && now.saturating_sub(pkt.since) >= HOLD_TIMEOUT_US {
+                cb(pkt);
+                pkt.valid = false;
+                self.counters.expired += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    fn next_hop(i: u8) -> IPAddr {
+        [10, 0, 0, i]
+    }
+
+    /// Holds a datagram of len bytes for next_hop i, whose first byte is tag
+    fn hold(queue: &mut HoldQueue, i: u8, tag: u8, len: usize, now: u64) -> Result<(), HoldDrop> {
+        let mut buf = [0u8; HOLD_PACKET_MAX + 1];
+        buf[0] = tag;
+        let datagram = Bytes::new(buf.as_mut_ptr(), len);
+        queue.hold(next_hop(i), &datagram, 1, [2, 0, 0, 0, 0, 1], false, now)
+    }
+
+    /// Tags of the packets released for next_hop i, in order
+    fn release(queue: &mut HoldQueue, i: u8) -> Vec<u8> {
+        let mut tags = Vec::new();
+        queue.release(&next_hop(i), |pkt| tags.push(pkt.datagram().u8_at(0)));
+        tags
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut queue = HoldQueue::new();
+
+        for tag in 0..HOLD_PER_NEXT_HOP as u8 {
+            hold(&mut queue, 0, tag, 64, 0).unwrap();
+        }
+        assert_eq!(hold(&mut queue, 0, 9, 64, 0), Err(HoldDrop::NextHopFull));
+        assert_eq!(hold(&mut queue, 1, 0, HOLD_PACKET_MAX + 1, 0), Err(HoldDrop::TooLarge));
+
+        // Other next hops fill the rest of the pool
+        for i in 1..(HOLD_POOL_SIZE / HOLD_PER_NEXT_HOP) as u8 {
+            for tag in 0..HOLD_PER_NEXT_HOP as u8 {
+                hold(&mut queue, i, tag, HOLD_PACKET_MAX, 0).unwrap();
+            }
+        }
+        assert_eq!(hold(&mut queue, 100, 0, 64, 0), Err(HoldDrop::PoolFull));
+
+        let cnt = &queue.counters;
+        assert_eq!((cnt.held, cnt.too_large, cnt.next_hop_full, cnt.pool_full), (HOLD_POOL_SIZE as u64, 1, 1, 1));
+
+        // Releasing a next hop makes room again
+        assert_eq!(release(&mut queue, 0).len(), HOLD_PER_NEXT_HOP);
+        hold(&mut queue, 100, 0, 64, 0).unwrap();
+    }
+
+    #[test]
+    fn test_release_order() {
+        let mut queue = HoldQueue::new();
+        hold(&mut queue, 0, 2, 64, 20).unwrap();
+        hold(&mut queue, 1, 9, 64, 0).unwrap();
+        hold(&mut queue, 0, 1, 64, 10).unwrap();
+        hold(&mut queue, 0, 3, 64, 30).unwrap();
+
+        assert_eq!(release(&mut queue, 0), [1, 2, 3]);
+        assert_eq!(release(&mut queue, 0), []);
+        assert_eq!(release(&mut queue, 1), [9]);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut queue = HoldQueue::new();
+        hold(&mut queue, 0, 1, 64, 0).unwrap();
+        hold(&mut queue, 1, 2, 64, 1_000_000).unwrap();
+
+        let expired = |queue: &mut HoldQueue, now: u64| {
+            let mut tags = Vec::new();
+            queue.poll(now, |pkt| tags.push(pkt.datagram().u8_at(0)));
+            tags
+        };
+
+        assert_eq!(expired(&mut queue, HOLD_TIMEOUT_US - 1), []);
+        assert_eq!(expired(&mut queue, HOLD_TIMEOUT_US), [1]);
+        assert_eq!(expired(&mut queue, HOLD_TIMEOUT_US + 1_000_000), [2]);
+        assert_eq!(queue.counters.expired, 2);
+        assert_eq!(release(&mut queue, 0), []);
+    }
+}
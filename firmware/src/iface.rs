@@ -0,0 +1,335 @@
+/*
+ * Per-interface configuration
+ *
+ */
+
+use crate::routing::IPAddr;
+
+#[derive(Clone, Copy)]
+pub struct IfaceConfig {
+    /// Prefix length of the subnet directly attached to the interface
+    pub prefix_len: u8,
+    /// Answer ARP requests for addresses routed through other interfaces
+    pub proxy_arp: bool,
+    /// Largest IP packet sent through the interface
+    pub mtu: u16,
+    /// Tell on-link hosts about better first hops with ICMP redirects
+    pub send_redirects: bool,
+    /// Advertise the interface address as a default router (RFC 1256)
+    pub router_ad: bool,
+    /// Seconds hosts may keep using an advertised address
+    pub ad_lifetime: u16,
+    /// Preference of the advertised address over other routers on the subnet
+    pub ad_preference: i32,
+}
+
+impl IfaceConfig {
+    pub const DEFAULT: IfaceConfig = IfaceConfig {
+        prefix_len: 24,
+        proxy_arp: false,
+        mtu: 1500,
+        send_redirects: true,
+        router_ad: false,
+        ad_lifetime: 1800,
+        ad_preference: 0,
+    };
+
+    /// If ip lies in the subnet of the interface addressed addr
+    pub fn on_link(&self, addr: &IPAddr, ip: &IPAddr) -> bool {
+        if self.prefix_len == 0 {
+            return true;
+        }
+
+        let mask = !0u32 << (32 - self.prefix_len as u32);
+        (u32::from_be_bytes(*addr) & mask) == (u32::from_be_bytes(*ip) & mask)
+    }
+
+    /**
+     * Fills in the network part of ip from the interface address addr if it is zero,
+     * as hosts that do not know their network yet use it for "this network" (RFC 792)
+     */
+    pub fn fill_network(&self, addr: &IPAddr, ip: &IPAddr) -> IPAddr {
+        if self.prefix_len == 0 {
+            return *ip;
+        }
+
+        let mask = !0u32 << (32 - self.prefix_len as u32);
+        let ip = u32::from_be_bytes(*ip);
+        if ip & mask != 0 {
+            return ip.to_be_bytes();
+        }
+
+        ((u32::from_be_bytes(*addr) & mask) | ip).to_be_bytes()
+    }
+}
+
+// RFC 5227 timings
+const PROBE_WAIT_US: u64 = 1_000_000;
+const PROBE_NUM: u8 = 3;
+const PROBE_INTERVAL_US: u64 = 1_000_000;
+const ANNOUNCE_WAIT_US: u64 = 2_000_000;
+const ANNOUNCE_NUM: u8 = 2;
+const ANNOUNCE_INTERVAL_US: u64 = 2_000_000;
+const DEFEND_INTERVAL_US: u64 = 10_000_000;
+
+#[derive(Clone, Copy, PartialEq)]
+enum AddrState {
+    Probing { sent: u8, next: u64 },
+    Announcing { sent: u8, next: u64 },
+    Ready,
+    /// Another host answered our probes, the address is kept but never announced
+    Conflict,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AddrAction {
+    /// Send an ARP probe: a request for our address with a zero sender address
+    Probe,
+    /// Send a gratuitous ARP: a request with our address as both sender and target
+    Announce,
+}
+
+/// Duplicate address detection and announcement of an interface address (RFC 5227)
+#[derive(Clone, Copy)]
+pub struct AddrCheck {
+    state: AddrState,
+    last_defend: u64,
+}
+
+impl AddrCheck {
+    /// Starts checking a newly configured address
+    pub fn new(now: u64) -> Self {
+        Self {
+            state: AddrState::Probing {
+                sent: 0,
+                next: now + PROBE_WAIT_US,
+            },
+            last_defend: 0,
+        }
+    }
+
+    pub fn is_probing(&self) -> bool {
+        match self.state {
+            AddrState::Probing { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn poll(&mut self, now: u64) -> Option<AddrAction> {
+        match self.state {
+            AddrState::Probing { sent, next } if now >= next => {
+                if sent < PROBE_NUM {
+                    self.state = AddrState::Probing {
+                        sent: sent + 1,
+                        next: now + PROBE_INTERVAL_US,
+                    };
+                    Some(AddrAction::Probe)
+                } else {
+                    self.state = AddrState::Announcing {
+                        sent: 0,
+                        next: now + ANNOUNCE_WAIT_US - PROBE_INTERVAL_US,
+                    };
+                    None
+                }
+            },
+            AddrState::Announcing { sent, next } if now >= next => {
+                self.state = if sent + 1 < ANNOUNCE_NUM {
+                    AddrState::Announcing {
+                        sent: sent + 1,
+                        next: now + ANNOUNCE_INTERVAL_US,
+                    }
+                } else {
+                    AddrState::Ready
+                };
+                Some(AddrAction::Announce)
+            },
+            _ => None,
+        }
+    }
+
+    /**
+     * Another host claims our address.
+     * Returns true if the address should be defended with an announcement,
+     * which is done at most once per DEFEND_INTERVAL_US.
+     */
+    pub fn conflict(&mut self, now: u64) -> bool {
+        match self.state {
+            AddrState::Probing { .. } | AddrState::Conflict => {
+                self.state = AddrState::Conflict;
+                false
+            },
+            _ => {
+                if self.last_defend != 0 && now.saturating_sub(self.last_defend) < DEFEND_INTERVAL_US {
+                    return false;
+                }
+
+                self.last_defend = now;
+                true
+            },
+        }
+    }
+}
+
+// RFC 1256 timings
+const MAX_INITIAL_ADVERT_INTERVAL_US: u64 = 16_000_000;
+const MAX_INITIAL_ADVERTS: u8 = 3;
+const MAX_ADVERT_INTERVAL_US: u64 = 600_000_000;
+const MIN_ADVERT_INTERVAL_US: u64 = MAX_ADVERT_INTERVAL_US * 3 / 4;
+const MAX_RESPONSE_DELAY_US: u64 = 2_000_000;
+
+/// Schedule of the periodic router advertisements of an interface
+#[derive(Clone, Copy)]
+pub struct AdvertTimer {
+    next: u64,
+    initial_left: u8,
+}
+
+impl AdvertTimer {
+    pub fn new(now: u64) -> Self {
+        Self {
+            next: now + jitter(now, MAX_INITIAL_ADVERT_INTERVAL_US),
+            initial_left: MAX_INITIAL_ADVERTS,
+        }
+    }
+
+    /// Returns true if an advertisement should be multicasted now
+    pub fn poll(&mut self, now: u64) -> bool {
+        if now < self.next {
+            return false;
+        }
+
+        let interval = if self.initial_left > 0 {
+            self.initial_left -= 1;
+            core::cmp::min(MAX_INITIAL_ADVERT_INTERVAL_US, MIN_ADVERT_INTERVAL_US + jitter(now, MAX_ADVERT_INTERVAL_US - MIN_ADVERT_INTERVAL_US))
+        } else {
+            MIN_ADVERT_INTERVAL_US + jitter(now, MAX_ADVERT_INTERVAL_US - MIN_ADVERT_INTERVAL_US)
+        };
+
+        self.next = now + interval;
+        true
+    }
+
+    /// A solicitation without a source address was received, multicast an advertisement soon
+    pub fn solicited(&mut self, now: u64) {
+        let at = now + jitter(now, MAX_RESPONSE_DELAY_US);
+        if at < self.next {
+            self.next = at;
+        }
+    }
+}
+
+/// Cheap spread in [0, span), enough to desynchronize routers sharing a subnet
+fn jitter(now: u64, span: u64) -> u64 {
+    (now ^ (now >> 17)).wrapping_mul(0x9E37_79B9_7F4A_7C15) % span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000;
+
+    #[test]
+    fn test_fill_network() {
+        let iface = IfaceConfig::DEFAULT;
+        let addr = [10, 0, 3, 1];
+
+        assert_eq!(iface.fill_network(&addr, &[0, 0, 0, 0]), [10, 0, 3, 0]);
+        assert_eq!(iface.fill_network(&addr, &[0, 0, 0, 7]), [10, 0, 3, 7]);
+        assert_eq!(iface.fill_network(&addr, &[192, 168, 0, 7]), [192, 168, 0, 7]);
+    }
+
+    #[test]
+    fn test_addr_check() {
+        let mut check = AddrCheck::new(0);
+
+        // Probes after a random wait, then at fixed intervals
+        assert!(check.poll(PROBE_WAIT_US - 1).is_none());
+        for i in 0..PROBE_NUM as u64 {
+            let at = PROBE_WAIT_US + i * PROBE_INTERVAL_US;
+            assert!(check.poll(at) == Some(AddrAction::Probe));
+            assert!(check.poll(at + PROBE_INTERVAL_US / 2).is_none());
+            assert!(check.is_probing());
+        }
+
+        // Announces ANNOUNCE_WAIT_US after the last probe
+        let last_probe = PROBE_WAIT_US + (PROBE_NUM as u64 - 1) * PROBE_INTERVAL_US;
+        assert!(check.poll(last_probe + PROBE_INTERVAL_US).is_none());
+        assert!(!check.is_probing());
+
+        let announce = last_probe + ANNOUNCE_WAIT_US;
+        assert!(check.poll(announce - 1).is_none());
+        assert!(check.poll(announce) == Some(AddrAction::Announce));
+        assert!(check.poll(announce + ANNOUNCE_INTERVAL_US - 1).is_none());
+        assert!(check.poll(announce + ANNOUNCE_INTERVAL_US) == Some(AddrAction::Announce));
+        assert!(check.poll(announce + 100 * S).is_none());
+
+        // Then defends the address at most once per DEFEND_INTERVAL_US
+        let now = announce + 100 * S;
+        assert!(check.conflict(now));
+        assert!(!check.conflict(now + DEFEND_INTERVAL_US - 1));
+        assert!(check.conflict(now + DEFEND_INTERVAL_US));
+    }
+
+    #[test]
+    fn test_addr_conflict_while_probing() {
+        let mut check = AddrCheck::new(10 * S);
+        assert!(check.poll(10 * S + PROBE_WAIT_US) == Some(AddrAction::Probe));
+
+        // The address is given up: neither probed further, announced, nor defended
+        assert!(!check.conflict(11 * S));
+        assert!(!check.is_probing());
+        for t in 12..30 {
+            assert!(check.poll(t * S).is_none());
+        }
+        assert!(!check.conflict(30 * S));
+    }
+
+    #[test]
+    fn test_advert_timer() {
+        let boot = 12_345_678;
+        let mut timer = AdvertTimer::new(boot);
+
+        // The first advertisement is sent within MAX_INITIAL_ADVERT_INTERVAL_US
+        let mut now = boot;
+        while !timer.poll(now) {
+            now += 1000;
+            assert!(now < boot + MAX_INITIAL_ADVERT_INTERVAL_US);
+        }
+
+        // The initial ones are MAX_INITIAL_ADVERT_INTERVAL_US apart
+        for _ in 0..MAX_INITIAL_ADVERTS {
+            assert!(!timer.poll(now + MAX_INITIAL_ADVERT_INTERVAL_US - 1));
+            now += MAX_INITIAL_ADVERT_INTERVAL_US;
+            assert!(timer.poll(now));
+        }
+
+        // The following ones between MIN_ and MAX_ADVERT_INTERVAL_US
+        for _ in 0..10 {
+            assert!(!timer.poll(now + MIN_ADVERT_INTERVAL_US - 1));
+            let prev = now;
+            while !timer.poll(now) {
+                now += S;
+            }
+            assert!(now - prev >= MIN_ADVERT_INTERVAL_US && now - prev <= MAX_ADVERT_INTERVAL_US);
+        }
+
+        // A solicitation brings the next one within MAX_RESPONSE_DELAY_US, never later
+        timer.solicited(now + S);
+        assert!(timer.next >= now + S && timer.next < now + S + MAX_RESPONSE_DELAY_US);
+        let next = timer.next;
+        timer.solicited(next - 1);
+        assert!(timer.next <= next);
+    }
+
+    #[test]
+    fn test_jitter() {
+        // Routers booted at different times do not advertise in lockstep
+        let delays: [u64; 8] = core::array::from_fn(|i| {
+            let boot = i as u64 * 1000 + 1;
+            AdvertTimer::new(boot).next - boot
+        });
+        assert!(delays.iter().all(|&delay| delay < MAX_INITIAL_ADVERT_INTERVAL_US));
+        assert!(delays.windows(2).all(|w| w[0] != w[1]));
+    }
+}
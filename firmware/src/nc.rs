@@ -6,92 +6,717 @@
 use crate::cmd::{Cmd, Op};
 use crate::util::*;
 
-const NC_ENT_COUNT: usize = 16;
+/// Entries per hash bucket
+const NC_WAYS: usize = 4;
 const NC_ENT_HW_COUNT: usize = 8;
+/// At least one hardware slot is always left for unpinned neighboors
+pub const NC_PIN_MAX: usize = NC_ENT_HW_COUNT - 1;
 
-#[derive(Default)]
+/// Time an entry stays reachable after its last confirmation
+pub const NC_REACHABLE_TIME_US: u64 = 30_000_000;
+/// Time a stale entry is kept and probed before it expires
+pub const NC_STALE_TIME_US: u64 = 10_000_000;
+
+const NC_PROBE_INTERVAL_US: u64 = 2_000_000;
+const NC_POLL_INTERVAL_US: u64 = 100_000;
+
+/// ARP requests sent for an unresolved neighboor before giving up
+const NC_ARP_RETRIES: u8 = 3;
+/// Delay before the first retry, doubled on each following one
+const NC_RETRY_BASE_US: u64 = 500_000;
+/// Time during which requests for a neighboor that did not answer are suppressed
+const NC_FAILED_HOLDOFF_US: u64 = 20_000_000;
+
+const NC_PORT_COUNT: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum NCState {
+    /// Waiting for an ARP reply
+    Incomplete,
+    Reachable,
+    /// Did not answer in time, requests are suppressed until next_try
+    Failed,
+}
+
+impl Default for NCState {
+    fn default() -> Self {
+        NCState::Incomplete
+    }
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
 pub struct NCEntry {
     pub ip: [u8; 4],
     pub mac: [u8; 6],
     pub port: u8,
     hardware_slot: Option<usize>,
     valid: bool,
+    /// Configured entries never age nor get replaced
+    is_static: bool,
+    /// Static entries kept in a hardware slot
+    pinned: bool,
+    state: NCState,
+    confirmed: u64,
+    last_probe: u64,
+    tries: u8,
+    next_try: u64,
+}
+
+impl NCEntry {
+    const EMPTY: NCEntry = NCEntry {
+        ip: [0; 4],
+        mac: [0; 6],
+        port: 0,
+        hardware_slot: None,
+        valid: false,
+        is_static: false,
+        pinned: false,
+        state: NCState::Incomplete,
+        confirmed: 0,
+        last_probe: 0,
+        tries: 0,
+        next_try: 0,
+    };
+
+    pub fn in_hardware(&self) -> bool {
+        self.hardware_slot.is_some()
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.is_static
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.state == NCState::Reachable
+    }
 }
 
-#[derive(Default)]
-pub struct NeighboorCache {
-    entries: [NCEntry; NC_ENT_COUNT],
-    nptr: usize,
-    nhwptr: usize,
+#[derive(Default, Clone, Copy)]
+struct HwSlot {
+    /// Entry currently programmed into this slot
+    owner: Option<usize>,
+    last_used: u64,
+    hits: u32,
 }
 
-impl NeighboorCache {
+/// ARP statistics of an interface
+#[derive(Default, Clone, Copy)]
+pub struct ArpCounters {
+    pub requests: u64,
+    pub retries: u64,
+    pub resolved: u64,
+    pub failures: u64,
+    /// Requests not sent because the neighboor was already being resolved, or has failed recently
+    pub suppressed: u64,
+}
+
+/// Outcome of NeighboorCache::resolve
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Resolve {
+    /// A new resolution started, an ARP request should be broadcast.
+    /// Holds the neighboor evicted to make room, whose MAC may no longer be used
+    Request(Option<NCEntry>),
+    /// The neighboor is already being resolved
+    Pending,
+    /// The neighboor did not answer recently
+    Failed,
+}
+
+pub enum NCEvent<'a> {
+    /// The entry is still incomplete, and the ARP request should be repeated
+    Request(&'a NCEntry),
+    /// The entry did not answer any request, packets waiting for it should be dropped
+    Failed(&'a NCEntry),
+    /// The entry is stale, and should be confirmed with an unicast ARP request
+    Probe(&'a NCEntry),
+    /// The entry was not confirmed in time and has been removed
+    Expired(&'a NCEntry),
+}
+
+/**
+ * Set-associative hash table of neighboors, holding BUCKETS * NC_WAYS entries.
+ * BUCKETS should be a power of 2.
+ *
+ * Entry indexes are stable until the entry is replaced.
+ */
+pub struct NeighboorCache<const BUCKETS: usize> {
+    entries: [[NCEntry; NC_WAYS]; BUCKETS],
+    slots: [HwSlot; NC_ENT_HW_COUNT],
+    pinned: [Option<[u8; 4]>; NC_PIN_MAX],
+    counters: [ArpCounters; NC_PORT_COUNT],
+    reachable_time: u64,
+    stale_time: u64,
+    next_poll: u64,
+}
+
+impl<const BUCKETS: usize> NeighboorCache<{BUCKETS}> {
+    pub fn new() -> Self {
+        assert!(BUCKETS & (BUCKETS - 1) == 0);
+
+        Self {
+            entries: [[NCEntry::EMPTY; NC_WAYS]; BUCKETS],
+            slots: Default::default(),
+            pinned: [None; NC_PIN_MAX],
+            counters: Default::default(),
+            reachable_time: NC_REACHABLE_TIME_US,
+            stale_time: NC_STALE_TIME_US,
+            next_poll: 0,
+        }
+    }
+
+    fn bucket(ip: &[u8; 4]) -> usize {
+        // Fibonacci hashing, so that hosts within the same subnet spread over all buckets
+        let h = u32::from_be_bytes(*ip).wrapping_mul(0x9E37_79B9);
+        (h >> 16) as usize & (BUCKETS - 1)
+    }
+
+    pub fn set_timeouts(&mut self, reachable_time: u64, stale_time: u64) {
+        self.reachable_time = reachable_time;
+        self.stale_time = stale_time;
+    }
+
+    /// Looks up a resolved neighboor
     pub fn lookup(&self, ip: &[u8; 4]) -> Option<usize> {
-        for i in 0..NC_ENT_COUNT {
-            let ent = &self.entries[i];
+        self.find(ip).filter(|&at| self.get(at).state == NCState::Reachable)
+    }
+
+    /// If ip has an entry, resolved or not
+    pub fn contains(&self, ip: &[u8; 4]) -> bool {
+        self.find(ip).is_some()
+    }
+
+    /// Looks up an entry of ip, whether it is resolved or not
+    fn find(&self, ip: &[u8; 4]) -> Option<usize> {
+        let bucket = Self::bucket(ip);
+        for way in 0..NC_WAYS {
+            let ent = &self.entries[bucket][way];
 
             if ent.valid && ent.ip == *ip {
-                return Some(i);
+                return Some(bucket * NC_WAYS + way);
             }
         }
 
         None
     }
 
+    pub fn counters(&self, port: u8) -> &ArpCounters {
+        &self.counters[port as usize]
+    }
+
+    pub fn hprint_counters(&self) {
+        for (port, cnt) in self.counters.iter().enumerate() {
+            hprint("ARP @ ");
+            hprint_dec(port as u64);
+            hprint(": req ");
+            hprint_dec(cnt.requests);
+            hprint(", retry ");
+            hprint_dec(cnt.retries);
+            hprint(", ok ");
+            hprint_dec(cnt.resolved);
+            hprint(", fail ");
+            hprint_dec(cnt.failures);
+            hprint(", suppressed ");
+            hprint_dec(cnt.suppressed);
+            hprint("\n\r");
+        }
+    }
+
     pub fn get(&self, at: usize) -> &NCEntry {
-        &self.entries[at]
+        &self.entries[at / NC_WAYS][at % NC_WAYS]
+    }
+
+    fn get_mut(&mut self, at: usize) -> &mut NCEntry {
+        &mut self.entries[at / NC_WAYS][at % NC_WAYS]
+    }
+
+    /**
+     * Picks the way to be replaced in ip's bucket: a free one, otherwise an unresolved one,
+     * otherwise the stale one confirmed the longest ago. Static and pinned entries are never picked.
+     */
+    fn victim(&self, ip: &[u8; 4], now: u64) -> Option<usize> {
+        let bucket = Self::bucket(ip);
+
+        let mut victim = None;
+        let mut victim_score = (0, 0);
+
+        for way in 0..NC_WAYS {
+            let ent = &self.entries[bucket][way];
+            if !ent.valid {
+                return Some(bucket * NC_WAYS + way);
+            }
+
+            if ent.is_static || self.is_pinned(&ent.ip) {
+                continue;
+            }
+
+            let age = now.saturating_sub(ent.confirmed);
+            let class = match ent.state {
+                NCState::Incomplete | NCState::Failed => 2,
+                NCState::Reachable if age >= self.reachable_time => 1,
+                NCState::Reachable => 0,
+            };
+            let score = (class, age);
+            if victim.is_none() || score > victim_score {
+                victim = Some(way);
+                victim_score = score;
+            }
+        }
+
+        victim.map(|way| bucket * NC_WAYS + way)
+    }
+
+    /**
+     * Overwrites the entry at, releasing the hardware slot of its previous occupant.
+     * Returns the previous occupant if it was a resolved neighboor.
+     */
+    fn replace(&mut self, at: usize, ent: NCEntry) -> Option<NCEntry> {
+        let prev = *self.get(at);
+        if prev.valid {
+            if let Some(hwidx) = prev.hardware_slot {
+                self.release_hardware(hwidx);
+            }
+        }
+
+        *self.get_mut(at) = ent;
+
+        hprint("NC slot ");
+        hprint_dec(at as u64);
+        hprint("\n\r");
+
+        Some(prev).filter(|prev| prev.valid && prev.is_resolved())
+    }
+
+    /**
+     * Inserts or refreshes the entry for ip, confirming its reachability.
+     * If the neighboor changed its MAC or port, both the entry and its hardware slot are updated.
+     *
+     * Returns the entry whose MAC may no longer be used: the previous one of ip if it changed,
+     * or the neighboor evicted to make room for ip.
+     */
+    pub fn put(&mut self, ip: [u8; 4], mac: [u8; 6], port: u8, now: u64) -> Option<NCEntry> {
+        if let Some(idx) = self.find(&ip) {
+            let ent = self.get_mut(idx);
+            ent.confirmed = now;
+
+            if ent.is_static {
+                if ent.mac != mac {
+                    hprint("NC static entry mismatch: ");
+                    hprint_ip(&ip);
+                    hprint(" claimed by ");
+                    hprint_mac(&mac);
+                    hprint("\n\r");
+                }
+
+                return None;
+            }
+
+            if ent.state != NCState::Reachable {
+                ent.state = NCState::Reachable;
+                ent.mac = mac;
+                ent.port = port;
+                self.counters[port as usize].resolved += 1;
+                return None;
+            }
+
+            if ent.mac == mac && ent.port == port {
+                return None;
+            }
+
+            hprint("NC changed: ");
+            hprint_ip(&ip);
+            hprint(" ");
+            hprint_mac(&ent.mac);
+            hprint(" -> ");
+            hprint_mac(&mac);
+            hprint("\n\r");
+
+            let prev = *ent;
+            ent.mac = mac;
+            ent.port = port;
+
+            if let Some(hwidx) = ent.hardware_slot {
+                Self::write_hardware_mac(hwidx, &mac);
+                Self::write_hardware_port(hwidx, port);
+            }
+
+            return Some(prev);
+        }
+
+        match self.victim(&ip, now) {
+            Some(at) => self.replace(at, NCEntry {
+                valid: true,
+                state: NCState::Reachable,
+                confirmed: now,
+                ip, mac, port,
+                ..NCEntry::EMPTY
+            }),
+            None => {
+                hprint("NC bucket full of static or pinned entries\n\r");
+                None
+            },
+        }
     }
 
-    pub fn put(&mut self, ip: [u8; 4], mac: [u8; 6], port: u8) {
-        if self.entries[self.nptr].valid {
-            if let Some(hwidx) = self.entries[self.nptr].hardware_slot {
-                let dis_cmd = Cmd {
-                    op: Op::DisableNCEnt,
-                    idx: hwidx as u8,
-                    data: [0; 6],
-                };
+    /**
+     * Starts resolving ip on port, after a lookup miss.
+     * Retries are driven by poll, with an exponential backoff. Neighboors that never answered
+     * are not asked again until NC_FAILED_HOLDOFF_US has passed.
+     */
+    pub fn resolve(&mut self, ip: [u8; 4], port: u8, now: u64) -> Resolve {
+        let existing = self.find(&ip);
 
-                dis_cmd.send();
+        if let Some(at) = existing {
+            let ent = self.get(at);
+            match ent.state {
+                NCState::Reachable => return Resolve::Pending,
+                NCState::Incomplete => {
+                    self.counters[port as usize].suppressed += 1;
+                    return Resolve::Pending;
+                },
+                NCState::Failed if now < ent.next_try => {
+                    self.counters[port as usize].suppressed += 1;
+                    return Resolve::Failed;
+                },
+                NCState::Failed => {},
             }
         }
 
-        self.entries[self.nptr] = NCEntry {
+        let at = match existing.or_else(|| self.victim(&ip, now)) {
+            Some(at) => at,
+            None => return Resolve::Failed,
+        };
+
+        let evicted = self.replace(at, NCEntry {
             valid: true,
-            hardware_slot: None,
+            state: NCState::Incomplete,
+            confirmed: now,
+            tries: 1,
+            next_try: now + NC_RETRY_BASE_US,
+            ip, port,
+            ..NCEntry::EMPTY
+        });
+        self.counters[port as usize].requests += 1;
+
+        Resolve::Request(evicted)
+    }
+
+    /**
+     * Ages all entries. Stale entries are reported for probing every NC_PROBE_INTERVAL_US,
+     * and entries unconfirmed for longer than reachable + stale time are removed.
+     * Incomplete entries are reported for retries, until they fail.
+     */
+    pub fn poll<F: FnMut(NCEvent)>(&mut self, now: u64, mut cb: F) {
+        if now < self.next_poll {
+            return;
+        }
+        self.next_poll = now + NC_POLL_INTERVAL_US;
+
+        for ent in self.entries.iter_mut().flat_map(|b| b.iter_mut()) {
+            if !ent.valid || ent.is_static {
+                continue;
+            }
+
+            let counters = &mut self.counters[ent.port as usize];
+
+            match ent.state {
+                NCState::Incomplete if now >= ent.next_try => {
+                    if ent.tries >= NC_ARP_RETRIES {
+                        ent.state = NCState::Failed;
+                        ent.next_try = now + NC_FAILED_HOLDOFF_US;
+                        counters.failures += 1;
+                        cb(NCEvent::Failed(ent));
+                    } else {
+                        ent.next_try = now + (NC_RETRY_BASE_US << ent.tries);
+                        ent.tries += 1;
+                        counters.retries += 1;
+                        cb(NCEvent::Request(ent));
+                    }
+                },
+                NCState::Failed if now >= ent.next_try => {
+                    ent.valid = false;
+                },
+                NCState::Reachable => {
+                    let age = now.saturating_sub(ent.confirmed);
+                    if age >= self.reachable_time + self.stale_time {
+                        if let Some(hwidx) = ent.hardware_slot {
+                            self.slots[hwidx] = HwSlot::default();
+                            Self::disable_hardware(hwidx);
+                        }
+
+                        ent.valid = false;
+                        ent.hardware_slot = None;
+                        cb(NCEvent::Expired(ent));
+                    } else if age >= self.reachable_time && now.saturating_sub(ent.last_probe) >= NC_PROBE_INTERVAL_US {
+                        ent.last_probe = now;
+                        cb(NCEvent::Probe(ent));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /**
+     * Replaces the set of next hops that are never evicted from hardware slots, busiest first.
+     * Only as many are kept as fit next to the pinned static entries.
+     */
+    pub fn set_pinned(&mut self, ips: &[[u8; 4]]) {
+        self.pinned = [None; NC_PIN_MAX];
+        for (pin, ip) in self.pinned.iter_mut().zip(ips.iter()) {
+            *pin = Some(*ip);
+        }
+
+        self.trim_pins();
+    }
+
+    fn is_static_pinned(&self, ip: &[u8; 4]) -> bool {
+        self.find(ip).map(|at| self.get(at).pinned).unwrap_or(false)
+    }
+
+    /// Drops the least busy next hops pinned by routing until at most NC_PIN_MAX neighboors are pinned
+    fn trim_pins(&mut self) {
+        let mut cnt = self.iter().filter(|ent| ent.pinned).count();
+
+        for i in 0..NC_PIN_MAX {
+            let ip = match self.pinned[i] {
+                Some(ip) => ip,
+                None => continue,
+            };
+
+            if self.is_static_pinned(&ip) {
+                continue;
+            }
+
+            if cnt < NC_PIN_MAX {
+                cnt += 1;
+            } else {
+                self.pinned[i] = None;
+            }
+        }
+    }
+
+    pub fn is_pinned(&self, ip: &[u8; 4]) -> bool {
+        self.pinned.iter().any(|pin| *pin == Some(*ip)) || self.is_static_pinned(ip)
+    }
+
+    /**
+     * Adds or overwrites a static entry. If pin is set, the entry is also written into a hardware slot
+     * that is never evicted, taking precedence over the next hops pinned by routing.
+     *
+     * Fails if the bucket of ip is full of static entries, or if too many static entries are already pinned,
+     * in which case the cache is left untouched. Otherwise returns the entry replaced in place,
+     * either the previous one of ip or an evicted neighboor, whose MAC may no longer be used.
+     */
+    pub fn add_static(&mut self, ip: [u8; 4], mac: [u8; 6], port: u8, pin: bool, now: u64) -> Result<Option<NCEntry>, ()> {
+        if pin {
+            let pinned = self.iter().filter(|ent| ent.pinned && ent.ip != ip).count();
+            if pinned >= NC_PIN_MAX {
+                return Err(());
+            }
+        }
+
+        let at = self.find(&ip).or_else(|| self.victim(&ip, now)).ok_or(())?;
+
+        let prev = self.replace(at, NCEntry {
+            valid: true,
+            is_static: true,
+            pinned: pin,
+            state: NCState::Reachable,
+            confirmed: now,
             ip, mac, port,
+            ..NCEntry::EMPTY
+        });
+
+        if pin {
+            self.trim_pins();
+
+            // At most NC_PIN_MAX entries are pinned, so an unpinned slot is left
+            let _ = self.write_hardware(at, now);
+        }
+
+        Ok(prev)
+    }
+
+    /// Removes the entry of ip, static or not, returning its MAC
+    pub fn remove(&mut self, ip: &[u8; 4]) -> Result<[u8; 6], ()> {
+        let at = self.find(ip).ok_or(())?;
+
+        if let Some(hwidx) = self.get(at).hardware_slot {
+            self.release_hardware(hwidx);
+        }
+
+        let ent = self.get_mut(at);
+        ent.valid = false;
+        Ok(ent.mac)
+    }
+
+    /// Removes all dynamic entries, and static ones too if include_static is set
+    pub fn flush(&mut self, include_static: bool) {
+        for hwidx in 0..NC_ENT_HW_COUNT {
+            if let Some(owner) = self.slots[hwidx].owner {
+                if include_static || !self.get(owner).is_static {
+                    self.release_hardware(hwidx);
+                }
+            }
+        }
+
+        for ent in self.entries.iter_mut().flat_map(|b| b.iter_mut()) {
+            if include_static || !ent.is_static {
+                ent.valid = false;
+            }
+        }
+    }
+
+    /// Iterates over all entries, including unresolved ones
+    pub fn iter(&self) -> impl Iterator<Item = &NCEntry> {
+        self.entries.iter().flat_map(|b| b.iter()).filter(|ent| ent.valid)
+    }
+
+    pub fn hprint_table(&self) {
+        for ent in self.iter() {
+            hprint_ip(&ent.ip);
+            hprint(" ");
+            if ent.is_resolved() {
+                hprint_mac(&ent.mac);
+            } else {
+                hprint("(incomplete)     ");
+            }
+            hprint(" @ ");
+            hprint_dec(ent.port as u64);
+            if ent.is_static {
+                hprint(" static");
+            }
+            if ent.pinned {
+                hprint(" pinned");
+            }
+            if let Some(hwidx) = ent.hardware_slot {
+                hprint(" hw ");
+                hprint_dec(hwidx as u64);
+            }
+            hprint("\n\r");
+        }
+    }
+
+    /// Records that traffic has been resolved through the entry at
+    pub fn touch(&mut self, at: usize, now: u64) {
+        if let Some(hwidx) = self.get(at).hardware_slot {
+            let slot = &mut self.slots[hwidx];
+            slot.last_used = now;
+            slot.hits = slot.hits.saturating_add(1);
+        }
+    }
+
+    /// Picks the hardware slot to be overwritten: a free one, otherwise the least recently used unpinned one
+    fn hardware_victim(&self) -> Option<usize> {
+        let mut victim: Option<usize> = None;
+
+        for (hwidx, slot) in self.slots.iter().enumerate() {
+            let owner = match slot.owner {
+                None => return Some(hwidx),
+                Some(owner) => owner,
+            };
+
+            if self.is_pinned(&self.get(owner).ip) {
+                continue;
+            }
+
+            victim = match victim {
+                Some(v) if self.slots[v].last_used <= slot.last_used => Some(v),
+                _ => Some(hwidx),
+            };
+        }
+
+        victim
+    }
+
+    fn release_hardware(&mut self, hwidx: usize) {
+        if let Some(owner) = self.slots[hwidx].owner {
+            self.get_mut(owner).hardware_slot = None;
+        }
+
+        self.slots[hwidx] = HwSlot::default();
+        Self::disable_hardware(hwidx);
+    }
+
+    fn disable_hardware(hwidx: usize) {
+        let dis_cmd = Cmd {
+            op: Op::DisableNCEnt,
+            idx: hwidx as u8,
+            data: [0; 6],
         };
 
-        hprint("NPTR");
-        hprint_dec(self.nptr as u64);
-        hprint("\n\r");
+        dis_cmd.send();
+    }
 
-        self.nptr = if self.nptr == NC_ENT_COUNT - 1 {
-            0
-        } else {
-            self.nptr + 1
+    fn write_hardware_mac(hwidx: usize, mac: &[u8; 6]) {
+        let mac_cmd = Cmd {
+            op: Op::WriteNCEntMAC,
+            idx: hwidx as u8,
+            data: [
+                mac[5],
+                mac[4],
+                mac[3],
+                mac[2],
+                mac[1],
+                mac[0],
+            ],
         };
+
+        mac_cmd.send();
     }
 
-    pub fn write_hardware(&mut self, at: usize) {
-        for ent in self.entries.iter_mut() {
-            if ent.valid && ent.hardware_slot == Some(self.nhwptr) {
-                ent.hardware_slot = None;
-                break;
-            }
+    fn write_hardware_port(hwidx: usize, port: u8) {
+        let port_cmd = Cmd {
+            op: Op::WriteNCEntPort,
+            idx: hwidx as u8,
+            data: [
+                port,
+                0,0,0,0,0
+            ],
+        };
+
+        port_cmd.send();
+    }
+
+    /**
+     * Programs the entry at into a hardware slot, unless it is already there.
+     * Fails if all slots are held by pinned entries, which are never evicted.
+     */
+    pub fn write_hardware(&mut self, at: usize, now: u64) -> Result<(), ()> {
+        if self.get(at).in_hardware() {
+            self.touch(at, now);
+            return Ok(());
+        }
+
+        let hwidx = match self.hardware_victim() {
+            Some(hwidx) => hwidx,
+            None => {
+                hprint("NC all hardware slots pinned\n\r");
+                return Err(());
+            },
+        };
+        if self.slots[hwidx].owner.is_some() {
+            hprint("| Evicting hardware slot ");
+            hprint_dec(hwidx as u64);
+            hprint(", hits: ");
+            hprint_dec(self.slots[hwidx].hits as u64);
+            hprint("\n\r");
+
+            self.release_hardware(hwidx);
         }
 
         hprint("| Writing IP: ");
-        hprint_ip(&self.entries[at].ip);
+        hprint_ip(&self.get(at).ip);
         hprint("\n\r");
 
         let ip_cmd = Cmd {
             op: Op::WriteNCEntIP,
-            idx: self.nhwptr as u8,
+            idx: hwidx as u8,
             data: [
-                self.entries[at].ip[3],
-                self.entries[at].ip[2],
-                self.entries[at].ip[1],
-                self.entries[at].ip[0],
+                self.get(at).ip[3],
+                self.get(at).ip[2],
+                self.get(at).ip[1],
+                self.get(at).ip[0],
                 0,
                 0,
             ],
@@ -100,57 +725,303 @@ impl NeighboorCache {
         ip_cmd.send();
 
         hprint("| Writing MAC: ");
-        hprint_mac(&self.entries[at].mac);
+        hprint_mac(&self.get(at).mac);
         hprint("\n\r");
 
-        let mac_cmd = Cmd {
-            op: Op::WriteNCEntMAC,
-            idx: self.nhwptr as u8,
-            data: [
-                self.entries[at].mac[5],
-                self.entries[at].mac[4],
-                self.entries[at].mac[3],
-                self.entries[at].mac[2],
-                self.entries[at].mac[1],
-                self.entries[at].mac[0],
-            ],
-        };
-
-        mac_cmd.send();
+        Self::write_hardware_mac(hwidx, &self.get(at).mac);
 
         hprint("| Writing port: ");
-        hprint_dec(self.entries[at].port as u64);
+        hprint_dec(self.get(at).port as u64);
         hprint("\n\r");
 
-        let port_cmd = Cmd {
-            op: Op::WriteNCEntPort,
-            idx: self.nhwptr as u8,
-            data: [
-                self.entries[at].port,
-                0,0,0,0,0
-            ],
-        };
-
-        port_cmd.send();
+        Self::write_hardware_port(hwidx, self.get(at).port);
 
         let en_cmd = Cmd {
             op: Op::EnableNCEnt,
-            idx: self.nhwptr as u8,
+            idx: hwidx as u8,
             data: [0; 6],
         };
 
         en_cmd.send();
 
-        self.entries[at].hardware_slot = Some(self.nhwptr);
+        self.get_mut(at).hardware_slot = Some(hwidx);
+        self.slots[hwidx] = HwSlot {
+            owner: Some(at),
+            last_used: now,
+            hits: 1,
+        };
 
-        hprint("NHWPTR");
-        hprint_dec(self.nhwptr as u64);
+        hprint("NHWSLOT");
+        hprint_dec(hwidx as u64);
         hprint("\n\r");
 
-        self.nhwptr = if self.nhwptr == NC_ENT_HW_COUNT-1 {
-            0
-        } else {
-            self.nhwptr + 1
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+    use super::*;
+
+    fn ip(i: u8) -> [u8; 4] {
+        [10, 0, 0, i]
+    }
+
+    fn mac(i: u8) -> [u8; 6] {
+        [2, 0, 0, 0, 0, i]
+    }
+
+    /// Events reported by a poll at now, as their initial and the IP of their entry
+    fn events<const BUCKETS: usize>(nc: &mut NeighboorCache<{BUCKETS}>, now: u64) -> Vec<(char, [u8; 4])> {
+        let mut events = Vec::new();
+        nc.poll(now, |ev| events.push(match ev {
+            NCEvent::Request(ent) => ('R', ent.ip),
+            NCEvent::Failed(ent) => ('F', ent.ip),
+            NCEvent::Probe(ent) => ('P', ent.ip),
+            NCEvent::Expired(ent) => ('E', ent.ip),
+        }));
+        events
+    }
+
+    fn in_hardware<const BUCKETS: usize>(nc: &NeighboorCache<{BUCKETS}>, i: u8) -> bool {
+        nc.get(nc.find(&ip(i)).unwrap()).in_hardware()
+    }
+
+    #[test]
+    fn test_bucket_hash() {
+        // Hosts of a subnet spread over all buckets
+        let mut used = [0; 16];
+        for i in 0..=255 {
+            used[NeighboorCache::<16>::bucket(&ip(i))] += 1;
+        }
+
+        assert!(used.iter().all(|&cnt| cnt > 0 && cnt < 32));
+    }
+
+    #[test]
+    fn test_put() {
+        // A single bucket, so that all neighboors compete for its ways
+        let mut nc = NeighboorCache::<1>::new();
+        for i in 0..NC_WAYS as u8 {
+            assert!(nc.put(ip(i), mac(i), 1, i as u64).is_none());
+        }
+
+        // Refreshing or moving a neighboor returns its previous entry only if it changed
+        assert!(nc.put(ip(1), mac(1), 1, 10).is_none());
+        let prev = nc.put(ip(1), mac(11), 2, 10).unwrap();
+        assert_eq!((prev.ip, prev.mac, prev.port), (ip(1), mac(1), 1));
+        assert_eq!(nc.get(nc.lookup(&ip(1)).unwrap()).mac, mac(11));
+
+        // The stale neighboor confirmed the longest ago is evicted and returned
+        let now = NC_REACHABLE_TIME_US + 20;
+        let evicted = nc.put(ip(9), mac(9), 1, now).unwrap();
+        assert_eq!((evicted.ip, evicted.mac), (ip(0), mac(0)));
+        assert!(!nc.contains(&ip(0)));
+        assert!(nc.lookup(&ip(9)).is_some());
+
+        // Resolving evicts the same way, and returns the evicted neighboor too
+        let evicted = match nc.resolve(ip(20), 1, now) {
+            Resolve::Request(Some(evicted)) => evicted,
+            res => panic!("unexpected {:?}", res),
+        };
+        assert_eq!((evicted.ip, evicted.mac), (ip(2), mac(2)));
+        assert!(!nc.contains(&ip(2)));
+
+        // Unresolved neighboors are evicted first, and are not returned
+        assert!(nc.put(ip(21), mac(21), 1, now).is_none());
+        assert!(!nc.contains(&ip(20)));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut nc = NeighboorCache::<16>::new();
+        let ms = 1000;
+
+        assert_eq!(nc.resolve(ip(1), 1, 0), Resolve::Request(None));
+        assert_eq!(nc.resolve(ip(1), 1, 0), Resolve::Pending);
+        assert!(nc.contains(&ip(1)));
+        assert!(nc.lookup(&ip(1)).is_none());
+
+        // Retries back off exponentially
+        assert_eq!(events(&mut nc, 0), []);
+        assert_eq!(events(&mut nc, 500 * ms), [('R', ip(1))]);
+        assert_eq!(events(&mut nc, 1400 * ms), []);
+        assert_eq!(events(&mut nc, 1500 * ms), [('R', ip(1))]);
+        assert_eq!(events(&mut nc, 3400 * ms), []);
+        assert_eq!(events(&mut nc, 3500 * ms), [('F', ip(1))]);
+
+        // No request is sent again during the holdoff
+        let holdoff_end = 3500 * ms + NC_FAILED_HOLDOFF_US;
+        assert_eq!(nc.resolve(ip(1), 1, 4000 * ms), Resolve::Failed);
+        assert_eq!(nc.resolve(ip(1), 1, holdoff_end), Resolve::Request(None));
+
+        let cnt = nc.counters(1);
+        assert_eq!((cnt.requests, cnt.retries, cnt.failures, cnt.suppressed), (2, 2, 1, 2));
+
+        // An answer resolves the entry
+        assert!(nc.put(ip(1), mac(1), 1, holdoff_end).is_none());
+        assert_eq!(nc.get(nc.lookup(&ip(1)).unwrap()).mac, mac(1));
+        assert_eq!(nc.resolve(ip(1), 1, holdoff_end), Resolve::Pending);
+        assert_eq!(nc.counters(1).resolved, 1);
+    }
+
+    #[test]
+    fn test_poll_aging() {
+        let mut nc = NeighboorCache::<16>::new();
+        nc.put(ip(1), mac(1), 1, 0);
+        nc.add_static(ip(2), mac(2), 1, false, 0).unwrap();
+
+        let stale = NC_REACHABLE_TIME_US;
+        assert_eq!(events(&mut nc, stale - NC_POLL_INTERVAL_US), []);
+        assert_eq!(events(&mut nc, stale), [('P', ip(1))]);
+        assert_eq!(events(&mut nc, stale + NC_POLL_INTERVAL_US), []);
+        assert_eq!(events(&mut nc, stale + NC_PROBE_INTERVAL_US), [('P', ip(1))]);
+
+        // Static entries never expire
+        let expiry = NC_REACHABLE_TIME_US + NC_STALE_TIME_US;
+        assert_eq!(events(&mut nc, expiry), [('E', ip(1))]);
+        assert!(!nc.contains(&ip(1)));
+        assert!(nc.lookup(&ip(2)).is_some());
+
+        // A confirmation keeps the entry reachable
+        nc.put(ip(3), mac(3), 1, expiry);
+        nc.put(ip(3), mac(3), 1, expiry + stale);
+        assert_eq!(events(&mut nc, expiry + stale + NC_POLL_INTERVAL_US), []);
+    }
+
+    #[test]
+    fn test_hardware_lru() {
+        let mut nc = NeighboorCache::<16>::new();
+        for i in 1..=NC_ENT_HW_COUNT as u8 + 2 {
+            nc.put(ip(i), mac(i), 1, 0);
         }
+
+        let write = |nc: &mut NeighboorCache<16>, i: u8, now: u64| {
+            nc.write_hardware(nc.lookup(&ip(i)).unwrap(), now)
+        };
+
+        for i in 1..=NC_ENT_HW_COUNT as u8 {
+            write(&mut nc, i, i as u64).unwrap();
+        }
+
+        // Writing an entry already in hardware only refreshes it
+        write(&mut nc, 1, 20).unwrap();
+        assert!(in_hardware(&nc, 1));
+
+        // The least recently used slot is taken over
+        write(&mut nc, 9, 30).unwrap();
+        assert!(!in_hardware(&nc, 2));
+        assert!(in_hardware(&nc, 9));
+
+        // Pinned next hops are skipped
+        nc.set_pinned(&[ip(3)]);
+        write(&mut nc, 2, 40).unwrap();
+        assert!(in_hardware(&nc, 3));
+        assert!(!in_hardware(&nc, 4));
+
+        // With the most pins allowed, the slots left unpinned keep being reused
+        let pins: Vec<_> = (1..=NC_ENT_HW_COUNT as u8).map(ip).collect();
+        nc.set_pinned(&pins);
+        for i in 1..NC_ENT_HW_COUNT as u8 {
+            write(&mut nc, i, 50 + i as u64).unwrap();
+        }
+        for i in 9..=10 {
+            write(&mut nc, i, 60 + i as u64).unwrap();
+            assert!(in_hardware(&nc, i));
+        }
+        assert!((1..NC_ENT_HW_COUNT as u8).all(|i| in_hardware(&nc, i)));
+
+        // Pins beyond NC_PIN_MAX are dropped, but if all slots were pinned none would be evicted
+        assert!(!nc.is_pinned(&ip(NC_ENT_HW_COUNT as u8)));
+        let at = nc.find(&ip(10)).unwrap();
+        nc.get_mut(at).pinned = true;
+        nc.put(ip(11), mac(11), 1, 80);
+        assert_eq!(write(&mut nc, 11, 80), Err(()));
+        assert!(in_hardware(&nc, 10));
+        assert!(!in_hardware(&nc, 11));
+    }
+
+    #[test]
+    fn test_pinned_victim() {
+        let mut nc = NeighboorCache::<1>::new();
+        for i in 0..NC_WAYS as u8 {
+            nc.put(ip(i), mac(i), 1, i as u64);
+        }
+        nc.set_pinned(&[ip(0)]);
+        nc.write_hardware(nc.lookup(&ip(0)).unwrap(), 0).unwrap();
+
+        // The pinned gateway is the stalest entry of the full bucket, but another one is evicted
+        let now = NC_REACHABLE_TIME_US + 10;
+        let evicted = nc.put(ip(9), mac(9), 1, now).unwrap();
+        assert_eq!(evicted.ip, ip(1));
+        assert!(in_hardware(&nc, 0));
+
+        // A bucket holding only pinned entries takes no new neighboor
+        let pins: Vec<_> = [0, 2, 3, 9].iter().map(|&i| ip(i)).collect();
+        nc.set_pinned(&pins);
+        assert!(nc.put(ip(10), mac(10), 1, now).is_none());
+        assert_eq!(nc.resolve(ip(10), 1, now), Resolve::Failed);
+        assert!(!nc.contains(&ip(10)));
+        assert!(in_hardware(&nc, 0));
+    }
+
+    #[test]
+    fn test_static() {
+        let mut nc = NeighboorCache::<1>::new();
+        nc.put(ip(1), mac(1), 1, 0);
+
+        // A dynamic entry is replaced in place, and returned
+        let prev = nc.add_static(ip(1), mac(11), 2, false, 0).unwrap().unwrap();
+        assert_eq!(prev.mac, mac(1));
+        assert!(nc.add_static(ip(2), mac(2), 1, true, 0).unwrap().is_none());
+        assert!(in_hardware(&nc, 2));
+
+        // Static entries are not changed by ARP
+        assert!(nc.put(ip(1), mac(21), 1, 0).is_none());
+        assert_eq!(nc.get(nc.lookup(&ip(1)).unwrap()).mac, mac(11));
+
+        // Nor evicted, even from a full bucket
+        nc.put(ip(3), mac(3), 1, 0);
+        nc.add_static(ip(4), mac(4), 1, false, 0).unwrap();
+        assert!(nc.add_static(ip(5), mac(5), 1, false, 0).unwrap().is_some());
+        assert!(!nc.contains(&ip(3)));
+        assert!(nc.add_static(ip(6), mac(6), 1, false, 0).is_err());
+        assert!(nc.put(ip(6), mac(6), 1, 0).is_none());
+        assert!(!nc.contains(&ip(6)));
+
+        nc.flush(false);
+        assert_eq!(nc.iter().count(), NC_WAYS);
+
+        assert_eq!(nc.remove(&ip(2)), Ok(mac(2)));
+        assert_eq!(nc.remove(&ip(2)), Err(()));
+        assert!(nc.slots.iter().all(|slot| slot.owner.is_none()));
+
+        nc.flush(true);
+        assert_eq!(nc.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_static_pins() {
+        let mut nc = NeighboorCache::<16>::new();
+        for i in 1..=3 {
+            nc.add_static(ip(i), mac(i), 1, true, 0).unwrap();
+        }
+
+        // Next hops pinned by routing only get the room left by static pins
+        let pins: Vec<_> = (10..10 + NC_PIN_MAX as u8).map(ip).collect();
+        nc.set_pinned(&pins);
+        assert_eq!(pins.iter().filter(|pin| nc.is_pinned(pin)).count(), NC_PIN_MAX - 3);
+
+        // And make room for new ones
+        for i in 4..=NC_PIN_MAX as u8 {
+            nc.add_static(ip(i), mac(i), 1, true, 0).unwrap();
+        }
+        assert!(pins.iter().all(|pin| !nc.is_pinned(pin)));
+        assert!(nc.add_static(ip(20), mac(20), 1, true, 0).is_err());
+
+        // Overwriting a pinned entry does not count it twice
+        assert!(nc.add_static(ip(1), mac(21), 1, true, 0).unwrap().is_some());
+        assert!((1..=NC_PIN_MAX as u8).all(|i| in_hardware(&nc, i)));
     }
 }
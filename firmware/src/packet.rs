@@ -0,0 +1,971 @@
+/*
+ * Zero-copy views over packets in buffer memory
+ *
+ * Views are checked against the length of the underlying buffer when created,
+ * every access is bounds checked and done volatilely, and multi-byte fields are big-endian.
+ */
+
+use crate::buf::EthType;
+use crate::checksum;
+use crate::data::arp::{ARP, HType, Oper};
+use crate::util::*;
+
+const IP_OUTGOING_TTL: u8 = 64;
+
+/// A range of buffer memory
+#[derive(Clone, Copy)]
+pub struct Bytes {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Bytes {
+    pub fn new(ptr: *mut u8, len: usize) -> Self {
+        Self { ptr, len }
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn check(&self, at: usize, len: usize) {
+        assert!(at + len <= self.len, "packet access out of bounds");
+    }
+
+    /// The len bytes from at
+    pub fn slice(&self, at: usize, len: usize) -> Bytes {
+        self.check(at, len);
+        Bytes::new(unsafe { self.ptr.offset(at as isize) }, len)
+    }
+
+    /// The len bytes from at, or None if they are not all in the range.
+    /// To be used where packet contents decide what is accessed.
+    pub fn get(&self, at: usize, len: usize) -> Option<Bytes> {
+        if at.checked_add(len)? > self.len {
+            return None;
+        }
+
+        Some(self.slice(at, len))
+    }
+
+    /// Everything from at
+    pub fn from(&self, at: usize) -> Bytes {
+        self.slice(at, self.len.saturating_sub(at))
+    }
+
+    pub fn u8_at(&self, at: usize) -> u8 {
+        self.check(at, 1);
+        unsafe { core::ptr::read_volatile(self.ptr.offset(at as isize)) }
+    }
+
+    /// The byte at at, or None if it is not in the range
+    pub fn get_u8(&self, at: usize) -> Option<u8> {
+        self.get(at, 1).map(|byte| byte.u8_at(0))
+    }
+
+    pub fn u16_at(&self, at: usize) -> u16 {
+        u16::from_be_bytes([self.u8_at(at), self.u8_at(at + 1)])
+    }
+
+    pub fn u32_at(&self, at: usize) -> u32 {
+        u32::from_be_bytes([self.u8_at(at), self.u8_at(at + 1), self.u8_at(at + 2), self.u8_at(at + 3)])
+    }
+
+    pub fn addr_at(&self, at: usize) -> [u8; 4] {
+        let mut ret = [0; 4];
+        for i in 0..4 {
+            ret[i] = self.u8_at(at + i);
+        }
+        ret
+    }
+
+    pub fn mac_at(&self, at: usize) -> [u8; 6] {
+        let mut ret = [0; 6];
+        for i in 0..6 {
+            ret[i] = self.u8_at(at + i);
+        }
+        ret
+    }
+
+    pub fn set_u8(&self, at: usize, value: u8) {
+        self.check(at, 1);
+        unsafe { core::ptr::write_volatile(self.ptr.offset(at as isize), value) }
+    }
+
+    pub fn set_u16(&self, at: usize, value: u16) {
+        self.set_slice(at, &value.to_be_bytes());
+    }
+
+    pub fn set_u32(&self, at: usize, value: u32) {
+        self.set_slice(at, &value.to_be_bytes());
+    }
+
+    pub fn set_slice(&self, at: usize, data: &[u8]) {
+        self.check(at, data.len());
+        for (i, byte) in data.iter().enumerate() {
+            self.set_u8(at + i, *byte);
+        }
+    }
+
+    /// Copies all of src to at
+    pub fn copy_from(&self, at: usize, src: &Bytes) {
+        self.check(at, src.len);
+        for i in 0..src.len {
+            self.set_u8(at + i, src.u8_at(i));
+        }
+    }
+
+    /// Fills the len bytes from at with value
+    pub fn fill(&self, at: usize, len: usize, value: u8) {
+        self.check(at, len);
+        for i in 0..len {
+            self.set_u8(at + i, value);
+        }
+    }
+
+    pub fn checksum(&self) -> u16 {
+        checksum::checksum(self.ptr, self.len)
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        checksum::verify(self.ptr, self.len)
+    }
+}
+
+/**
+ * A frame in a hardware buffer cell: MAC addresses, a VLAN tag whose ID is
+ * the port, the ethertype and the payload
+ */
+pub struct EthernetFrame {
+    bytes: Bytes,
+}
+
+const ETH_DEST: usize = 0;
+const ETH_SRC: usize = 6;
+const ETH_VLAN: usize = 12;
+const ETH_TYPE: usize = 16;
+pub const ETH_HEADER_LEN: usize = 18;
+
+impl EthernetFrame {
+    pub fn new(bytes: Bytes) -> Self {
+        bytes.check(0, ETH_HEADER_LEN);
+        Self { bytes }
+    }
+
+    pub fn dest(&self) -> [u8; 6] {
+        self.bytes.mac_at(ETH_DEST)
+    }
+
+    pub fn src(&self) -> [u8; 6] {
+        self.bytes.mac_at(ETH_SRC)
+    }
+
+    pub fn port(&self) -> u8 {
+        self.bytes.u8_at(ETH_VLAN + 3)
+    }
+
+    pub fn eth_type(&self) -> Option<EthType> {
+        // EthType values are in memory order
+        let raw = u16::from_le_bytes([self.bytes.u8_at(ETH_TYPE), self.bytes.u8_at(ETH_TYPE + 1)]);
+        [EthType::ARP, EthType::IPv4, EthType::IPv6].iter().cloned().find(|t| *t as u16 == raw)
+    }
+
+    pub fn set_dest(&self, mac: [u8; 6]) {
+        self.bytes.set_slice(ETH_DEST, &mac);
+    }
+
+    pub fn set_src(&self, mac: [u8; 6]) {
+        self.bytes.set_slice(ETH_SRC, &mac);
+    }
+
+    pub fn set_port(&self, port: u8) {
+        self.bytes.set_slice(ETH_VLAN, &[0x81, 0x00, 0x00, port]);
+    }
+
+    pub fn set_eth_type(&self, t: EthType) {
+        self.bytes.set_slice(ETH_TYPE, &(t as u16).to_le_bytes());
+    }
+
+    /// Everything after the header, up to the end of the buffer cell
+    pub fn payload(&self) -> Bytes {
+        self.bytes.from(ETH_HEADER_LEN)
+    }
+}
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum IPProto {
+    ICMP = 0x01,
+    IGMP = 0x02,
+    TCP = 0x06,
+    UDP = 0x11,
+}
+
+pub const IP_OPT_END: u8 = 0;
+pub const IP_OPT_NOP: u8 = 1;
+pub const IP_OPT_RECORD_ROUTE: u8 = 7;
+pub const IP_OPT_TIMESTAMP: u8 = 68;
+pub const IP_OPT_ROUTER_ALERT: u8 = 148;
+
+/// Options with this bit set are copied into every fragment
+const IP_OPT_COPIED: u8 = 0x80;
+
+const IP_FLAG_DF: u16 = 0x4000;
+const IP_FLAG_MF: u16 = 0x2000;
+const IP_FRAG_OFFSET_MASK: u16 = 0x1FFF;
+
+// Timestamp option flags
+const IP_TS_ONLY: u8 = 0;
+const IP_TS_ADDR: u8 = 1;
+const IP_TS_PRESPEC: u8 = 3;
+
+/// An option in the header of a datagram, type and length bytes included
+pub struct IPv4Option {
+    pub kind: u8,
+    pub bytes: Bytes,
+}
+
+/// Walks the options of a header, stopping at End of Options or at a malformed option
+pub struct OptionIter {
+    header: Bytes,
+    at: usize,
+}
+
+impl Iterator for OptionIter {
+    type Item = IPv4Option;
+
+    fn next(&mut self) -> Option<IPv4Option> {
+        loop {
+            if self.at >= self.header.len() {
+                return None;
+            }
+
+            let kind = self.header.u8_at(self.at);
+            match kind {
+                IP_OPT_END => return None,
+                IP_OPT_NOP => {
+                    self.at += 1;
+                    continue;
+                },
+                _ => {},
+            }
+
+            if self.at + 2 > self.header.len() {
+                return None;
+            }
+
+            let len = self.header.u8_at(self.at + 1) as usize;
+            if len < 2 || self.at + len > self.header.len() {
+                self.at = self.header.len();
+                return None;
+            }
+
+            let bytes = self.header.slice(self.at, len);
+            self.at += len;
+            return Some(IPv4Option { kind, bytes });
+        }
+    }
+}
+
+/// Why an incoming IPv4 datagram was dropped before being handled
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IPv4Drop {
+    /// The frame cannot hold a minimal header
+    Truncated,
+    BadVersion,
+    /// IHL below 5 words or beyond the frame
+    BadHeaderLen,
+    /// Total length shorter than the header or longer than the frame
+    BadTotalLen,
+    BadChecksum,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct IPv4Drops {
+    pub truncated: u64,
+    pub version: u64,
+    pub header_len: u64,
+    pub total_len: u64,
+    pub checksum: u64,
+}
+
+impl IPv4Drops {
+    pub fn count(&mut self, reason: IPv4Drop) {
+        match reason {
+            IPv4Drop::Truncated => self.truncated += 1,
+            IPv4Drop::BadVersion => self.version += 1,
+            IPv4Drop::BadHeaderLen => self.header_len += 1,
+            IPv4Drop::BadTotalLen => self.total_len += 1,
+            IPv4Drop::BadChecksum => self.checksum += 1,
+        }
+    }
+
+    pub fn hprint(&self) {
+        hprint("IPv4 drops: truncated ");
+        hprint_dec(self.truncated);
+        hprint(", version ");
+        hprint_dec(self.version);
+        hprint(", header length ");
+        hprint_dec(self.header_len);
+        hprint(", total length ");
+        hprint_dec(self.total_len);
+        hprint(", checksum ");
+        hprint_dec(self.checksum);
+        hprint("\n\r");
+    }
+}
+
+/// An IPv4 datagram, or the room for one being built
+pub struct Ipv4Packet {
+    bytes: Bytes,
+}
+
+impl Ipv4Packet {
+    /**
+     * Checks the header of the datagram at the start of bytes, which is what the frame
+     * carries after the Ethernet header. Trailing link-layer padding is cut off.
+     */
+    pub fn new_checked(bytes: Bytes) -> Result<Self, IPv4Drop> {
+        if bytes.len() < 20 {
+            return Err(IPv4Drop::Truncated);
+        }
+
+        let ver_ihl = bytes.u8_at(0);
+        if ver_ihl >> 4 != 4 {
+            return Err(IPv4Drop::BadVersion);
+        }
+
+        let header_len = (ver_ihl & 0xF) as usize * 4;
+        if header_len < 20 || header_len > bytes.len() {
+            return Err(IPv4Drop::BadHeaderLen);
+        }
+
+        let total_len = bytes.u16_at(2) as usize;
+        if total_len < header_len || total_len > bytes.len() {
+            return Err(IPv4Drop::BadTotalLen);
+        }
+
+        if !bytes.slice(0, header_len).verify_checksum() {
+            return Err(IPv4Drop::BadChecksum);
+        }
+
+        Ok(Self { bytes: bytes.slice(0, total_len) })
+    }
+
+    /// A datagram already checked, or written by us
+    pub fn new_unchecked(bytes: Bytes) -> Self {
+        Self { bytes }
+    }
+
+    /// The whole datagram
+    pub fn bytes(&self) -> Bytes {
+        self.bytes.slice(0, core::cmp::min(self.total_len(), self.bytes.len()))
+    }
+
+    pub fn proto(&self) -> u8 {
+        self.bytes.u8_at(9)
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.bytes.u8_at(8)
+    }
+
+    pub fn id(&self) -> u16 {
+        self.bytes.u16_at(4)
+    }
+
+    pub fn src(&self) -> [u8; 4] {
+        self.bytes.addr_at(12)
+    }
+
+    pub fn dest(&self) -> [u8; 4] {
+        self.bytes.addr_at(16)
+    }
+
+    /// Length of the header including options
+    pub fn header_len(&self) -> u16 {
+        (self.bytes.u8_at(0) & 0xF) as u16 * 4
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.bytes.u16_at(2) as usize
+    }
+
+    pub fn payload_len(&self) -> u16 {
+        self.bytes.u16_at(2).saturating_sub(self.header_len())
+    }
+
+    pub fn payload(&self) -> Bytes {
+        self.bytes.slice(self.header_len() as usize, self.payload_len() as usize)
+    }
+
+    pub fn icmp(&self) -> Option<IcmpMessage> {
+        if self.proto() != IPProto::ICMP as u8 {
+            return None;
+        }
+
+        IcmpMessage::new_checked(self.payload())
+    }
+
+    pub fn udp(&self) -> Option<UdpDatagram> {
+        if self.proto() != IPProto::UDP as u8 {
+            return None;
+        }
+
+        UdpDatagram::new_checked(self.payload())
+    }
+
+    pub fn options(&self) -> OptionIter {
+        OptionIter {
+            header: self.bytes.slice(0, self.header_len() as usize),
+            at: 20,
+        }
+    }
+
+    /// If the datagram carries a Router Alert option, asking routers to look into it (RFC 2113)
+    pub fn router_alert(&self) -> bool {
+        self.options().any(|opt| opt.kind == IP_OPT_ROUTER_ALERT)
+    }
+
+    /**
+     * Adds our address and the current time to the Record Route and Timestamp
+     * options of a datagram handled locally. Full options are left as is,
+     * except for the overflow count of timestamps.
+     */
+    pub fn process_options(&mut self, addr: [u8; 4], timestamp: u32) {
+        if self.header_len() == 20 {
+            return;
+        }
+
+        for opt in self.options() {
+            let opt_len = opt.bytes.len();
+            if opt_len < 3 {
+                continue;
+            }
+
+            let pointer = opt.bytes.u8_at(2) as usize;
+
+            match opt.kind {
+                IP_OPT_RECORD_ROUTE if pointer >= 4 && pointer + 3 <= opt_len => {
+                    opt.bytes.set_slice(pointer - 1, &addr);
+                    opt.bytes.set_u8(2, (pointer + 4) as u8);
+                },
+                IP_OPT_TIMESTAMP if opt_len >= 4 && pointer >= 5 => {
+                    let oflw_flg = opt.bytes.u8_at(3);
+                    let (oflw, flg) = (oflw_flg >> 4, oflw_flg & 0xF);
+                    let entry = if flg == IP_TS_ONLY { 4 } else { 8 };
+
+                    if pointer + entry - 1 > opt_len {
+                        if oflw < 0xF {
+                            opt.bytes.set_u8(3, ((oflw + 1) << 4) | flg);
+                        }
+                        continue;
+                    }
+
+                    let at = pointer - 1;
+                    let ts_at = match flg {
+                        IP_TS_ONLY => at,
+                        IP_TS_ADDR => {
+                            opt.bytes.set_slice(at, &addr);
+                            at + 4
+                        },
+                        // Only stamped when the next prespecified address is ours
+                        IP_TS_PRESPEC if opt.bytes.addr_at(at) == addr => at + 4,
+                        _ => continue,
+                    };
+
+                    opt.bytes.set_u32(ts_at, timestamp);
+                    opt.bytes.set_u8(2, (pointer + entry) as u8);
+                },
+                _ => {},
+            }
+        }
+
+        self.fill_chksum();
+    }
+
+    /**
+     * Copies the Record Route and Timestamp options of this datagram to dst,
+     * as options of a reply (RFC 1122 3.2.2.6).
+     *
+     * Returns the length of the copied options, padded to whole words
+     */
+    pub fn copy_reply_options(&self, dst: &Bytes) -> u16 {
+        let mut len = 0;
+
+        for opt in self.options() {
+            if opt.kind != IP_OPT_RECORD_ROUTE && opt.kind != IP_OPT_TIMESTAMP {
+                continue;
+            }
+
+            dst.copy_from(len, &opt.bytes);
+            len += opt.bytes.len();
+        }
+
+        // Pad with End of Options
+        let padded = (len + 3) / 4 * 4;
+        dst.fill(len, padded - len, IP_OPT_END);
+
+        padded as u16
+    }
+
+    fn frag_field(&self) -> u16 {
+        self.bytes.u16_at(6)
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.frag_field() & IP_FLAG_DF != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.frag_field() & IP_FLAG_MF != 0
+    }
+
+    /// Offset of the payload in the original datagram, in bytes
+    pub fn frag_offset(&self) -> usize {
+        (self.frag_field() & IP_FRAG_OFFSET_MASK) as usize * 8
+    }
+
+    /// If the datagram is only part of one
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.frag_offset() != 0
+    }
+
+    /**
+     * Rewrites the total length and fragment fields, keeping the Don't Fragment flag.
+     * offset is in bytes and has to be a multiple of 8.
+     */
+    pub fn set_fragment(&mut self, payload_len: u16, offset: usize, more: bool) {
+        let mut field = (self.frag_field() & IP_FLAG_DF) | (offset / 8) as u16;
+        if more {
+            field |= IP_FLAG_MF;
+        }
+
+        self.bytes.set_u16(2, payload_len + self.header_len());
+        self.bytes.set_u16(6, field);
+        self.fill_chksum();
+    }
+
+    /**
+     * Writes to dst the fragment carrying len bytes of the payload from offset.
+     * Fragments after the first only keep the options flagged to be copied (RFC 791).
+     *
+     * Returns the length of the fragment
+     */
+    pub fn write_fragment(&self, dst: &Bytes, offset: usize, len: usize, more: bool) -> u16 {
+        dst.copy_from(0, &self.bytes.slice(0, 20));
+
+        let mut header_len = 20;
+        for opt in self.options() {
+            if offset != 0 && opt.kind & IP_OPT_COPIED == 0 {
+                continue;
+            }
+
+            dst.copy_from(header_len, &opt.bytes);
+            header_len += opt.bytes.len();
+        }
+
+        let padded = (header_len + 3) / 4 * 4;
+        dst.fill(header_len, padded - header_len, IP_OPT_END);
+        let header_len = padded;
+
+        dst.set_u8(0, (4 << 4) | (header_len / 4) as u8);
+        dst.copy_from(header_len, &self.payload().slice(offset, len));
+
+        let mut frag = Ipv4Packet::new_unchecked(*dst);
+        frag.set_fragment(len as u16, self.frag_offset() + offset, more || self.more_fragments());
+
+        (header_len + len) as u16
+    }
+
+    pub fn fill_chksum(&mut self) {
+        let header = self.bytes.slice(0, self.header_len() as usize);
+        header.set_u16(10, 0);
+        header.set_u16(10, header.checksum());
+    }
+
+    /// Decrements the TTL of a forwarded datagram, updating the checksum incrementally (RFC 1624)
+    pub fn decrement_ttl(&mut self) {
+        // TTL and protocol share a word
+        let old = self.bytes.u16_at(8);
+        let new = old - 0x0100;
+        self.bytes.set_u16(8, new);
+        self.bytes.set_u16(10, checksum::update(self.bytes.u16_at(10), old, new));
+    }
+
+    /// Overrides the TTL of an outgoing packet, leaving the checksum to fill_chksum
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.bytes.set_u8(8, ttl);
+    }
+
+    /// Sets the type of service of an outgoing packet, leaving the checksum to fill_chksum
+    pub fn set_tos(&mut self, tos: u8) {
+        self.bytes.set_u8(1, tos);
+    }
+
+    /// Sets the identification of an outgoing packet, leaving the checksum to fill_chksum
+    pub fn set_id(&mut self, id: u16) {
+        self.bytes.set_u16(4, id);
+    }
+
+    /**
+     * Writes the header of an outgoing datagram, followed by options_len bytes of options already written.
+     * The checksum is left to fill_chksum, once all fields are set.
+     */
+    pub fn outgoing_with_options(&mut self, proto: IPProto, payload_len: u16, src: [u8; 4], dest: [u8; 4], options_len: u16) {
+        let header_len = 20 + options_len;
+
+        self.bytes.fill(0, 20, 0);
+        self.bytes.set_u8(0, (4 << 4) | (header_len / 4) as u8);
+        self.bytes.set_u16(2, payload_len + header_len);
+        self.bytes.set_u8(8, IP_OUTGOING_TTL);
+        self.bytes.set_u8(9, proto as u8);
+        self.bytes.set_slice(12, &src);
+        self.bytes.set_slice(16, &dest);
+    }
+}
+
+pub const ICMP_HEADER_LEN: usize = 8;
+
+/// An ICMP message: type, code, checksum, 4 type-specific bytes, then data
+pub struct IcmpMessage {
+    bytes: Bytes,
+}
+
+impl IcmpMessage {
+    pub fn new_checked(bytes: Bytes) -> Option<Self> {
+        if bytes.len() < ICMP_HEADER_LEN {
+            return None;
+        }
+
+        Some(Self { bytes })
+    }
+
+    /// Room for a message to be written, as long as bytes
+    pub fn allocate(bytes: Bytes) -> Self {
+        bytes.check(0, ICMP_HEADER_LEN);
+        Self { bytes }
+    }
+
+    pub fn r#type(&self) -> u8 {
+        self.bytes.u8_at(0)
+    }
+
+    pub fn code(&self) -> u8 {
+        self.bytes.u8_at(1)
+    }
+
+    /// The type-specific bytes after the checksum
+    pub fn rest(&self) -> [u8; 4] {
+        self.bytes.addr_at(4)
+    }
+
+    pub fn data(&self) -> Bytes {
+        self.bytes.from(ICMP_HEADER_LEN)
+    }
+
+    pub fn set_type(&self, r#type: u8) {
+        self.bytes.set_u8(0, r#type);
+    }
+
+    pub fn set_code(&self, code: u8) {
+        self.bytes.set_u8(1, code);
+    }
+
+    pub fn set_rest(&self, rest: [u8; 4]) {
+        self.bytes.set_slice(4, &rest);
+    }
+
+    pub fn verify(&self) -> bool {
+        self.bytes.verify_checksum()
+    }
+
+    pub fn fill_chksum(&self) {
+        self.bytes.set_u16(2, 0);
+        self.bytes.set_u16(2, self.bytes.checksum());
+    }
+}
+
+pub const UDP_HEADER_LEN: usize = 8;
+
+pub struct UdpDatagram {
+    bytes: Bytes,
+}
+
+impl UdpDatagram {
+    /// Checks the length field against the payload of the datagram in bytes
+    pub fn new_checked(bytes: Bytes) -> Option<Self> {
+        if bytes.len() < UDP_HEADER_LEN {
+            return None;
+        }
+
+        let len = bytes.u16_at(4) as usize;
+        if len < UDP_HEADER_LEN || len > bytes.len() {
+            return None;
+        }
+
+        Some(Self { bytes: bytes.slice(0, len) })
+    }
+
+    /// Room for a datagram to be written, as long as bytes
+    pub fn allocate(bytes: Bytes) -> Self {
+        bytes.check(0, UDP_HEADER_LEN);
+        Self { bytes }
+    }
+
+    pub fn src_port(&self) -> u16 {
+        self.bytes.u16_at(0)
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        self.bytes.u16_at(2)
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn payload(&self) -> Bytes {
+        self.bytes.from(UDP_HEADER_LEN)
+    }
+
+    /// Checks the checksum with the pseudo-header of a datagram from src to dest
+    pub fn verify(&self, src: &[u8; 4], dest: &[u8; 4]) -> bool {
+        // No checksum was computed by the sender
+        if self.bytes.u16_at(6) == 0 {
+            return true;
+        }
+
+        let mut sum = checksum::pseudo_header(src, dest, IPProto::UDP as u8, self.len() as u16);
+        sum.add_raw(self.bytes.as_ptr(), self.len());
+        sum.is_valid()
+    }
+
+    /// Writes the ports and the length of the whole datagram
+    pub fn set_header(&self, src_port: u16, dest_port: u16) {
+        self.bytes.set_u16(0, src_port);
+        self.bytes.set_u16(2, dest_port);
+        self.bytes.set_u16(4, self.len() as u16);
+    }
+
+    /// Computes the checksum with the pseudo-header of a datagram from src to dest
+    pub fn fill_chksum(&self, src: &[u8; 4], dest: &[u8; 4]) {
+        self.bytes.set_u16(6, 0);
+
+        let mut sum = checksum::pseudo_header(src, dest, IPProto::UDP as u8, self.len() as u16);
+        sum.add_raw(self.bytes.as_ptr(), self.len());
+
+        // Zero means no checksum, its one's complement twin is sent instead (RFC 768)
+        let sum = match sum.finish() {
+            0 => 0xFFFF,
+            sum => sum,
+        };
+        self.bytes.set_u16(6, sum);
+    }
+}
+
+pub const ARP_LEN: usize = 28;
+
+/// An Ethernet / IPv4 ARP packet (RFC 826)
+pub struct ArpPacket {
+    bytes: Bytes,
+}
+
+impl ArpPacket {
+    /// Checks the fixed fields, so that the packet can be read as an Ethernet / IPv4 ARP
+    pub fn new_checked(bytes: Bytes) -> Option<Self> {
+        if bytes.len() < ARP_LEN {
+            return None;
+        }
+
+        let oper = bytes.u16_at(6);
+        let valid = bytes.u16_at(0) == 1
+            && bytes.u16_at(2) == 0x0800
+            && bytes.u8_at(4) == 6
+            && bytes.u8_at(5) == 4
+            && (oper == 1 || oper == 2);
+
+        if !valid {
+            return None;
+        }
+
+        Some(Self { bytes: bytes.slice(0, ARP_LEN) })
+    }
+
+    /// Room for a packet to be written
+    pub fn allocate(bytes: Bytes) -> Self {
+        Self { bytes: bytes.slice(0, ARP_LEN) }
+    }
+
+    pub fn read(&self) -> ARP {
+        ARP {
+            htype: HType::Eth,
+            ptype: EthType::IPv4,
+            hlen: 6,
+            plen: 4,
+            op: if self.bytes.u16_at(6) == 1 { Oper::Req } else { Oper::Reply },
+            sha: self.bytes.mac_at(8),
+            spa: self.bytes.addr_at(14),
+            tha: self.bytes.mac_at(18),
+            tpa: self.bytes.addr_at(24),
+        }
+    }
+
+    pub fn write(&self, arp: &ARP) {
+        self.bytes.set_u16(0, 1);
+        self.bytes.set_u16(2, 0x0800);
+        self.bytes.set_u8(4, 6);
+        self.bytes.set_u8(5, 4);
+        self.bytes.set_u16(6, if arp.op == Oper::Req { 1 } else { 2 });
+        self.bytes.set_slice(8, &arp.sha);
+        self.bytes.set_slice(14, &arp.spa);
+        self.bytes.set_slice(18, &arp.tha);
+        self.bytes.set_slice(24, &arp.tpa);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(buf: &mut [u8], proto: IPProto, payload: &[u8]) -> Ipv4Packet {
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+        let (mut ip, body) = (Ipv4Packet::new_unchecked(bytes), bytes.from(20));
+        body.set_slice(0, payload);
+        ip.outgoing_with_options(proto, payload.len() as u16, [192, 168, 0, 2], [10, 0, 0, 1], 0);
+        ip.fill_chksum();
+        ip
+    }
+
+    #[test]
+    fn test_ipv4_checked() {
+        let mut buf = [0u8; 64];
+        datagram(&mut buf, IPProto::TCP, &[0; 12]);
+
+        // Link-layer padding is cut off
+        let ip = Ipv4Packet::new_checked(Bytes::new(buf.as_mut_ptr(), buf.len())).ok().unwrap();
+        assert_eq!(ip.bytes().len(), 32);
+        assert_eq!(ip.payload().len(), 12);
+        assert_eq!(ip.src(), [192, 168, 0, 2]);
+        assert_eq!(ip.ttl(), IP_OUTGOING_TTL);
+
+        let check = |buf: &mut [u8], len: usize| Ipv4Packet::new_checked(Bytes::new(buf.as_mut_ptr(), len)).err();
+        assert_eq!(check(&mut buf, 19), Some(IPv4Drop::Truncated));
+        assert_eq!(check(&mut buf, 31), Some(IPv4Drop::BadTotalLen));
+
+        buf[8] -= 1;
+        assert_eq!(check(&mut buf, 64), Some(IPv4Drop::BadChecksum));
+
+        buf[0] = 0x46;
+        assert_eq!(check(&mut buf, 20), Some(IPv4Drop::BadHeaderLen));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_out_of_bounds() {
+        let mut buf = [0u8; 28];
+        let ip = datagram(&mut buf, IPProto::UDP, &[0; 8]);
+        ip.payload().u8_at(8);
+    }
+
+    #[test]
+    fn test_get() {
+        let mut buf = [0u8; 28];
+        let ip = datagram(&mut buf, IPProto::UDP, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let payload = ip.payload();
+
+        assert_eq!(payload.get_u8(7), Some(8));
+        assert_eq!(payload.get_u8(8), None);
+        assert_eq!(payload.get(4, 4).map(|b| b.u32_at(0)), Some(0x05060708));
+        assert!(payload.get(4, 5).is_none());
+        assert!(payload.get(usize::MAX, 2).is_none());
+    }
+
+    #[test]
+    fn test_options() {
+        let mut buf = [0u8; 32];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+        // Record Route with room for one address, then a NOP
+        bytes.set_slice(20, &[IP_OPT_RECORD_ROUTE, 7, 4, 0, 0, 0, 0, IP_OPT_NOP, IP_OPT_END, 0, 0, 0]);
+        bytes.set_u8(0, 0x48);
+        bytes.set_u16(2, 32);
+
+        let mut ip = Ipv4Packet::new_unchecked(bytes);
+        assert_eq!(ip.options().count(), 1);
+
+        ip.process_options([10, 0, 0, 1], 0);
+        assert_eq!(&buf[22..27], &[8, 10, 0, 0, 1]);
+
+        // A full option is left as is
+        ip.process_options([10, 0, 0, 2], 0);
+        assert_eq!(&buf[22..27], &[8, 10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_option_pointer_overflow() {
+        let mut buf = [0xAAu8; 40];
+        buf[0] = 0x48;
+        buf[2..4].copy_from_slice(&40u16.to_be_bytes());
+        // Record Route and timestamp only, both pointing far past their end, then End of Options
+        buf[20..32].copy_from_slice(&[
+            IP_OPT_RECORD_ROUTE, 7, 0xFF, 0, 0, 0, 0,
+            IP_OPT_TIMESTAMP, 4, 0xFF, IP_TS_ONLY,
+            IP_OPT_END,
+        ]);
+        let before = buf;
+
+        let mut ip = Ipv4Packet::new_unchecked(Bytes::new(buf.as_mut_ptr(), buf.len()));
+        ip.process_options([10, 0, 0, 1], 0x12345678);
+
+        // Only the overflow count of the timestamp changes, nothing is written past the options
+        assert_eq!(&buf[20..30], &before[20..30]);
+        assert_eq!(buf[30], 0x10 | IP_TS_ONLY);
+        assert_eq!(&buf[31..], &before[31..]);
+    }
+
+    #[test]
+    fn test_udp_verify() {
+        let mut buf = [0u8; 40];
+        let payload = [0x02, 0x08, 0x02, 0x08, 0x00, 0x0c, 0x00, 0x00, 0x02, 0x02, 0x00, 0x00];
+        let ip = datagram(&mut buf, IPProto::UDP, &payload);
+
+        // No checksum
+        let udp = ip.udp().unwrap();
+        assert_eq!(udp.len(), 12);
+        assert!(udp.verify(&ip.src(), &ip.dest()));
+
+        let mut sum = checksum::pseudo_header(&ip.src(), &ip.dest(), IPProto::UDP as u8, 12);
+        sum.add_slice(&payload);
+        ip.payload().set_u16(6, sum.finish());
+        assert!(ip.udp().unwrap().verify(&ip.src(), &ip.dest()));
+
+        ip.payload().set_u8(11, 1);
+        assert!(!ip.udp().unwrap().verify(&ip.src(), &ip.dest()));
+
+        // Length beyond the datagram
+        ip.payload().set_u16(4, 13);
+        assert!(ip.udp().is_none());
+    }
+
+    #[test]
+    fn test_arp() {
+        let mut buf = [0u8; ARP_LEN];
+        let bytes = Bytes::new(buf.as_mut_ptr(), buf.len());
+        assert!(ArpPacket::new_checked(bytes).is_none());
+
+        let req = ARP::request([1, 2, 3, 4, 5, 6], [10, 0, 0, 2], [0; 6], [10, 0, 0, 1]);
+        ArpPacket::allocate(bytes).write(&req);
+        assert_eq!(&buf[0..8], &[0, 1, 8, 0, 6, 4, 0, 1]);
+
+        let arp = ArpPacket::new_checked(bytes).unwrap().read();
+        assert!(arp.op == Oper::Req);
+        assert_eq!(arp.sha, req.sha);
+        assert_eq!(arp.tpa, req.tpa);
+
+        assert!(ArpPacket::new_checked(bytes.slice(0, ARP_LEN - 1)).is_none());
+    }
+}
@@ -0,0 +1,311 @@
+/*
+ * Reassembly of fragmented datagrams addressed to the router
+ *
+ */
+
+use crate::packet::{Bytes, Ipv4Packet};
+use crate::routing::IPAddr;
+
+const REASM_SLOTS: usize = 4;
+/// Largest reassembled datagram, header included
+pub const REASM_DATAGRAM_MAX: usize = 4096;
+/// Fragments of a datagram not completed in time are dropped.
+/// RFC 1122 suggests 60 to 120s, but slots are scarce here.
+const REASM_TIMEOUT_US: u64 = 15_000_000;
+
+/// Room kept in front of the payload for the header of the first fragment
+const HEADER_MAX: usize = 60;
+/// Fragment offsets are counted in blocks of 8 bytes
+const BLOCK: usize = 8;
+/// Blocks fitting after the room for the header, the last one possibly partial
+const BLOCKS: usize = (REASM_DATAGRAM_MAX - HEADER_MAX + BLOCK - 1) / BLOCK;
+
+pub struct ReasmSlot {
+    /// Port and source MAC the first fragment arrived from
+    pub port: u8,
+    pub src: [u8; 6],
+    /// If the first fragment arrived in a broadcast or multicast frame
+    pub link_broadcast: bool,
+    ip_src: IPAddr,
+    ip_dest: IPAddr,
+    id: u16,
+    proto: u8,
+    /// Length of the header of the first fragment, 0 until it arrives
+    header_len: usize,
+    /// Length of the payload, known once the last fragment arrives
+    payload_len: Option<usize>,
+    blocks: [u64; (BLOCKS + 63) / 64],
+    since: u64,
+    valid: bool,
+    data: [u8; REASM_DATAGRAM_MAX],
+}
+
+impl ReasmSlot {
+    const EMPTY: ReasmSlot = ReasmSlot {
+        port: 0,
+        src: [0; 6],
+        link_broadcast: false,
+        ip_src: [0; 4],
+        ip_dest: [0; 4],
+        id: 0,
+        proto: 0,
+        header_len: 0,
+        payload_len: None,
+        blocks: [0; (BLOCKS + 63) / 64],
+        since: 0,
+        valid: false,
+        data: [0; REASM_DATAGRAM_MAX],
+    };
+
+    /// The part of the datagram in reassembly received so far from its start, if its first fragment has arrived
+    pub fn datagram(&self) -> Option<Bytes> {
+        if self.header_len == 0 {
+            return None;
+        }
+
+        let ptr = unsafe { self.data.as_ptr().offset((HEADER_MAX - self.header_len) as isize) };
+        Some(Bytes::new(ptr as *mut u8, self.received_len()))
+    }
+
+    /// Bytes of the datagram received so far from its start
+    pub fn received_len(&self) -> usize {
+        let mut blocks = 0;
+        while blocks < BLOCKS && self.has_block(blocks) {
+            blocks += 1;
+        }
+
+        // The last block is only partially part of the datagram
+        let received = self.header_len + blocks * BLOCK;
+        match self.payload_len {
+            Some(len) => received.min(self.header_len + len),
+            None => received,
+        }
+    }
+
+    fn matches(&self, ip: &Ipv4Packet) -> bool {
+        self.valid
+            && self.id == ip.id()
+            && self.proto == ip.proto()
+            && self.ip_src == ip.src()
+            && self.ip_dest == ip.dest()
+    }
+
+    fn has_block(&self, block: usize) -> bool {
+        self.blocks[block / 64] & (1 << (block % 64)) != 0
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.payload_len {
+            Some(len) if self.header_len != 0 => (0..(len + BLOCK - 1) / BLOCK).all(|b| self.has_block(b)),
+            _ => false,
+        }
+    }
+}
+
+pub enum Reasm {
+    /// The fragment was stored, more are needed
+    Incomplete,
+    /// The datagram is complete, valid until the next call to add
+    Complete(Ipv4Packet),
+    /// The fragment cannot be part of a datagram we can reassemble
+    Dropped,
+}
+
+pub struct Reassembly {
+    slots: [ReasmSlot; REASM_SLOTS],
+}
+
+impl Reassembly {
+    pub fn new() -> Self {
+        Self {
+            slots: [ReasmSlot::EMPTY; REASM_SLOTS],
+        }
+    }
+
+    /**
+     * Stores the fragment ip, received through port from the neighboor src,
+     * in a broadcast or multicast frame if link_broadcast is set.
+     * When no slot is free, the oldest datagram in reassembly is given up.
+     */
+    pub fn add(&mut self, ip: &Ipv4Packet, port: u8, src: [u8; 6], link_broadcast: bool, now: u64) -> Reasm {
+        let offset = ip.frag_offset();
+        let len = ip.payload_len() as usize;
+        let more = ip.more_fragments();
+
+        // Only the last fragment may end off a block boundary
+        if (more && len % BLOCK != 0) || HEADER_MAX + offset + len > REASM_DATAGRAM_MAX {
+            return Reasm::Dropped;
+        }
+
+        let idx = match self.slots.iter().position(|slot| slot.matches(ip)) {
+            Some(idx) => idx,
+            None => {
+                let idx = match self.slots.iter().position(|slot| !slot.valid) {
+                    Some(idx) => idx,
+                    None => (0..REASM_SLOTS).min_by_key(|&i| self.slots[i].since).unwrap(),
+                };
+
+                let slot = &mut self.slots[idx];
+                slot.port = port;
+                slot.src = src;
+                slot.link_broadcast = link_broadcast;
+                slot.ip_src = ip.src();
+                slot.ip_dest = ip.dest();
+                slot.id = ip.id();
+                slot.proto = ip.proto();
+                slot.header_len = 0;
+                slot.payload_len = None;
+                slot.blocks = [0; (BLOCKS + 63) / 64];
+                slot.since = now;
+                slot.valid = true;
+                idx
+            },
+        };
+
+        let slot = &mut self.slots[idx];
+
+        if !more {
+            slot.payload_len = Some(offset + len);
+        }
+
+        let bytes = ip.bytes();
+        let header_len = ip.header_len() as usize;
+        if offset == 0 {
+            slot.port = port;
+            slot.src = src;
+            slot.link_broadcast = link_broadcast;
+            slot.header_len = header_len;
+            for i in 0..header_len {
+                slot.data[HEADER_MAX - header_len + i] = bytes.u8_at(i);
+            }
+        }
+
+        // Overlapping data is overwritten by the latest fragment
+        let payload = ip.payload();
+        for i in 0..len {
+            slot.data[HEADER_MAX + offset + i] = payload.u8_at(i);
+        }
+
+        for block in offset / BLOCK..(offset + len + BLOCK - 1) / BLOCK {
+            slot.blocks[block / 64] |= 1 << (block % 64);
+        }
+
+        if !slot.is_complete() {
+            return Reasm::Incomplete;
+        }
+
+        slot.valid = false;
+        let datagram = slot.datagram().unwrap();
+        let mut whole = Ipv4Packet::new_unchecked(datagram);
+        whole.set_fragment(slot.payload_len.unwrap() as u16, 0, false);
+
+        Reasm::Complete(whole)
+    }
+
+    /// Gives up datagrams not completed within REASM_TIMEOUT_US, handing them to cb
+    pub fn poll<F: FnMut(&ReasmSlot)>(&mut self, now: u64, mut cb: F) {
+        for slot in self.slots.iter_mut() {
+            if slot.valid && now.saturating_sub(slot.since) >= REASM_TIMEOUT_US {
+                cb(slot);
+                slot.valid = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::IPProto;
+
+    const SRC_MAC: [u8; 6] = [2, 0, 0, 0, 0, 1];
+
+    /// Writes to buf the fragment of a datagram from 192.168.0.2 carrying payload at offset
+    fn fragment(buf: &mut [u8], payload: &[u8], offset: usize, more: bool) -> Ipv4Packet {
+        let bytes = Bytes::new(buf.as_mut_ptr(), 20 + payload.len());
+        bytes.set_slice(20, payload);
+
+        let mut ip = Ipv4Packet::new_unchecked(bytes);
+        ip.outgoing_with_options(IPProto::UDP, payload.len() as u16, [192, 168, 0, 2], [10, 0, 0, 1], 0);
+        ip.set_fragment(payload.len() as u16, offset, more);
+        ip
+    }
+
+    fn add(reasm: &mut Reassembly, payload: &[u8], offset: usize, more: bool, now: u64) -> Reasm {
+        let mut buf = [0u8; 128];
+        let ip = fragment(&mut buf, payload, offset, more);
+        reasm.add(&ip, 1, SRC_MAC, false, now)
+    }
+
+    fn assert_complete(result: Reasm, payload: &[u8]) {
+        let whole = match result {
+            Reasm::Complete(whole) => whole,
+            _ => panic!("datagram not complete"),
+        };
+
+        assert_eq!(whole.total_len(), 20 + payload.len());
+        assert_eq!(whole.bytes().len(), 20 + payload.len());
+        assert!(!whole.is_fragment());
+        assert!(whole.bytes().slice(0, 20).verify_checksum());
+        for (i, &b) in payload.iter().enumerate() {
+            assert_eq!(whole.payload().u8_at(i), b);
+        }
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut reasm = Reassembly::new();
+        let payload: [u8; 40] = core::array::from_fn(|i| i as u8);
+
+        // Last fragment first, then one overlapping both others
+        assert!(matches!(add(&mut reasm, &payload[32..], 32, false, 0), Reasm::Incomplete));
+        assert!(matches!(add(&mut reasm, &payload[16..40], 16, true, 0), Reasm::Incomplete));
+        assert_complete(add(&mut reasm, &payload[..24], 0, true, 0), &payload);
+    }
+
+    #[test]
+    fn test_unaligned_last() {
+        let mut reasm = Reassembly::new();
+        let payload: [u8; 21] = core::array::from_fn(|i| i as u8);
+
+        // Only the last fragment may end off a block boundary
+        assert!(matches!(add(&mut reasm, &payload[..5], 0, true, 0), Reasm::Dropped));
+
+        assert!(matches!(add(&mut reasm, &payload[..16], 0, true, 0), Reasm::Incomplete));
+        assert_complete(add(&mut reasm, &payload[16..], 16, false, 0), &payload);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut reasm = Reassembly::new();
+        let room = REASM_DATAGRAM_MAX - HEADER_MAX;
+        let last = room / BLOCK * BLOCK;
+
+        assert!(matches!(add(&mut reasm, &[0; BLOCK], last - BLOCK, true, 0), Reasm::Incomplete));
+        assert!(matches!(add(&mut reasm, &[0; BLOCK], last, false, 0), Reasm::Dropped));
+        assert!(matches!(add(&mut reasm, &[0; 5], last, false, 0), Reasm::Dropped));
+        assert!(matches!(add(&mut reasm, &[0; 4], last, false, 0), Reasm::Incomplete));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut reasm = Reassembly::new();
+        let payload = [0x55u8; 16];
+        assert!(matches!(add(&mut reasm, &payload[..8], 0, true, 0), Reasm::Incomplete));
+
+        let mut expired = 0;
+        reasm.poll(REASM_TIMEOUT_US - 1, |_| expired += 1);
+        assert_eq!(expired, 0);
+
+        reasm.poll(REASM_TIMEOUT_US, |slot| {
+            assert_eq!(slot.port, 1);
+            assert_eq!(slot.src, SRC_MAC);
+            assert_eq!(slot.received_len(), 28);
+            expired += 1;
+        });
+        assert_eq!(expired, 1);
+
+        // The first fragment was given up along with its slot
+        assert!(matches!(add(&mut reasm, &payload[8..], 8, false, REASM_TIMEOUT_US), Reasm::Incomplete));
+    }
+}
@@ -35,10 +35,73 @@ pub struct Rule {
     pub if_index: u8,
 }
 
+/**
+ * Collects the next hops that should stay in the hardware neighboor table:
+ * the default gateway, and next hops referenced by at least min_refs rules.
+ * Busier next hops come first.
+ *
+ * Returns the number of next hops written into out
+ */
+pub fn busiest_next_hops(rules: &[Rule], min_refs: usize, out: &mut [IPAddr]) -> usize {
+    const MAX_DISTINCT: usize = 64;
+    let mut counts: [(IPAddr, usize); MAX_DISTINCT] = [([0; 4], 0); MAX_DISTINCT];
+    let mut distinct = 0;
+    let mut gateway = None;
+
+    for rule in rules {
+        // Broadcast next hops are placeholders for ignored routes
+        if rule.next == [255, 255, 255, 255] || rule.next == [0, 0, 0, 0] {
+            continue;
+        }
+
+        if rule.len == 0 {
+            gateway = Some(rule.next);
+        }
+
+        match counts[0..distinct].iter_mut().find(|(ip, _)| *ip == rule.next) {
+            Some((_, cnt)) => *cnt += 1,
+            None if distinct < MAX_DISTINCT => {
+                counts[distinct] = (rule.next, 1);
+                distinct += 1;
+            },
+            None => {},
+        }
+    }
+
+    counts[0..distinct].sort_unstable_by(|a, b| b.1.cmp(&a.1));
+
+    let mut written = 0;
+    if let Some(gw) = gateway {
+        if out.len() > 0 {
+            out[0] = gw;
+            written = 1;
+        }
+    }
+
+    for (ip, cnt) in counts[0..distinct].iter() {
+        if written == out.len() || *cnt < min_refs {
+            break;
+        }
+
+        if Some(*ip) != gateway {
+            out[written] = *ip;
+            written += 1;
+        }
+    }
+
+    written
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    pub next: IPAddr,
+    pub if_index: u8,
+}
+
 #[derive(Default, Clone, Copy)]
 pub struct Trie {
     next: [Option<NonNull<Trie>>; 1 << TRIE_BITLEN],
-    value: Option<IPAddr>,
+    value: Option<Route>,
 }
 
 impl Trie {
@@ -61,7 +124,10 @@ impl Trie {
 
     fn apply_rule<const LEN: usize>(&mut self, store: &mut TrieBuf<{LEN}>, rule: &Rule, depth: u8) {
         if depth >= rule.len {
-            self.value = Some(rule.next);
+            self.value = Some(Route {
+                next: rule.next,
+                if_index: rule.if_index,
+            });
         } else {
             let bitmask = (ip_to_u32(&rule.prefix) >> (32 - TRIE_BITLEN - depth)) & ((1 << TRIE_BITLEN) - 1);
             let left = rule.len - depth;
@@ -96,10 +162,15 @@ impl Trie {
     }
 
     pub fn lookup(&self, addr: &IPAddr) -> Option<IPAddr> {
+        self.lookup_route(addr).map(|r| r.next)
+    }
+
+    /// Looks up the next hop of addr together with the interface it is reached through
+    pub fn lookup_route(&self, addr: &IPAddr) -> Option<Route> {
         self.inner_lookup(addr, 0)
     }
 
-    fn inner_lookup(&self, addr: &IPAddr, depth: u8) -> Option<IPAddr> {
+    fn inner_lookup(&self, addr: &IPAddr, depth: u8) -> Option<Route> {
         if depth == 32 {
             return self.value;
         }
@@ -143,32 +214,17 @@ impl<const LEN: usize> TrieBuf<{LEN}> {
 
 #[test]
 fn test_routing() {
+    let rule = |prefix: IPAddr, len: u8, next: IPAddr, if_index: u8| Rule {
+        prefix, len, next, if_index,
+        metric: 1,
+    };
+
     let mut rules = [
-        Rule {
-            prefix: [10,0,1,0],
-            len: 24,
-            next: [192,168,1,1],
-        },
-        Rule {
-            prefix: [10,0,2,0],
-            len: 24,
-            next: [192,168,2,1],
-        },
-        Rule {
-            prefix: [10,0,0,0],
-            len: 16,
-            next: [192,168,3,1],
-        },
-        Rule {
-            prefix: [0,0,0,0],
-            len: 0,
-            next: [192,168,4,1],
-        },
-        Rule {
-            prefix: [10,0,1,255],
-            len: 31,
-            next: [192,168,5,1],
-        },
+        rule([10,0,1,0], 24, [192,168,1,1], 1),
+        rule([10,0,2,0], 24, [192,168,2,1], 2),
+        rule([10,0,0,0], 16, [192,168,3,1], 3),
+        rule([0,0,0,0], 0, [192,168,4,1], 0),
+        rule([10,0,1,255], 31, [192,168,5,1], 1),
     ];
 
     rules.sort_by(|a, b| a.len.cmp(&b.len));
@@ -177,24 +233,57 @@ fn test_routing() {
         println!("len: {}", i.len);
     }
 
-    let buf = [Default::default(); 1024];
-    let mut trie_buf = TrieBuf::from(buf);
-    let trie = Trie::from_rules(&mut trie_buf, &rules);
+    let mut trie_buf = TrieBuf::<1024>::new();
+    let trie = Trie::from_rules(&mut trie_buf, &mut rules);
+    let trie = unsafe { trie.as_ref() };
 
     let cases = [
-        ([1,2,3,4], [192,168,4,1]),
-        ([10,1,2,3], [192,168,4,1]),
-        ([10,0,2,3], [192,168,2,1]),
-        ([10,0,1,1], [192,168,1,1]),
-        ([10,0,4,3], [192,168,3,1]),
-        ([10,0,100,3], [192,168,3,1]),
-        ([10,0,1,255], [192,168,5,1]),
-        ([10,0,1,254], [192,168,5,1]),
-        ([10,0,1,253], [192,168,1,1]),
+        ([1,2,3,4], [192,168,4,1], 0),
+        ([10,1,2,3], [192,168,4,1], 0),
+        ([10,0,2,3], [192,168,2,1], 2),
+        ([10,0,1,1], [192,168,1,1], 1),
+        ([10,0,4,3], [192,168,3,1], 3),
+        ([10,0,100,3], [192,168,3,1], 3),
+        ([10,0,1,255], [192,168,5,1], 1),
+        ([10,0,1,254], [192,168,5,1], 1),
+        ([10,0,1,253], [192,168,1,1], 1),
     ];
 
-    for (from, to) in cases.iter() {
+    for (from, to, if_index) in cases.iter() {
         println!("Testing from {:?}", from);
         assert_eq!(trie.lookup(from).as_ref(), Some(to));
+        assert_eq!(trie.lookup_route(from), Some(Route {
+            next: *to,
+            if_index: *if_index,
+        }));
     }
 }
+
+#[test]
+fn test_busiest_next_hops() {
+    let rule = |prefix: IPAddr, len: u8, next: IPAddr| Rule {
+        prefix, len, next,
+        metric: 1,
+        if_index: 0,
+    };
+
+    let rules = [
+        rule([10,0,1,0], 24, [192,168,1,2]),
+        rule([10,0,2,0], 24, [192,168,2,2]),
+        rule([10,0,3,0], 24, [192,168,2,2]),
+        rule([10,0,4,0], 24, [192,168,2,2]),
+        rule([10,0,5,0], 24, [192,168,3,2]),
+        rule([10,0,6,0], 24, [192,168,3,2]),
+        rule([0,0,0,0], 0, [192,168,0,2]),
+        rule([10,1,0,0], 16, [255,255,255,255]),
+        rule([10,2,0,0], 16, [255,255,255,255]),
+    ];
+
+    let mut out = [[0; 4]; 4];
+    assert_eq!(busiest_next_hops(&rules, 2, &mut out), 3);
+    assert_eq!(out[0..3], [[192,168,0,2], [192,168,2,2], [192,168,3,2]]);
+
+    let mut out = [[0; 4]; 2];
+    assert_eq!(busiest_next_hops(&rules, 1, &mut out), 2);
+    assert_eq!(out, [[192,168,0,2], [192,168,2,2]]);
+}
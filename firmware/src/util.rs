@@ -1,5 +1,6 @@
 // const FREQ: u64 = 50_000_000;
 
+#[cfg(not(test))]
 const SERIAL_BASE: usize = 0xFFFF00000000;
 const CLOCK_FREQ: u64 = 50;
 
@@ -7,6 +8,7 @@ pub fn hprint_setup() {
     // TODO: enable interrupt
 }
 
+#[cfg(not(test))]
 pub fn hprint_char(c: u8) {
     unsafe {
         core::ptr::write_volatile((SERIAL_BASE + 4) as *mut u8, c);
@@ -22,6 +24,10 @@ pub fn hprint_char(c: u8) {
     }
 }
 
+/// Host stub
+#[cfg(test)]
+pub fn hprint_char(_c: u8) {}
+
 pub fn hprint_bytes(cs: &[u8]) {
     for c in cs {
         hprint_char(*c);
@@ -104,4 +110,53 @@ pub fn hprint_ip(s: &[u8; 4]) {
 
 pub fn cur_time() -> u64 {
     riscv::register::mcycle::read64() / CLOCK_FREQ
-}
\ No newline at end of file
+}
+
+/// Unix time in us at cur_time() == 0, unknown until set_wall_time is called
+static mut WALL_OFFSET: Option<u64> = None;
+
+/// Aligns the wall clock, given the current Unix time in us
+pub fn set_wall_time(unix_us: u64) {
+    unsafe { WALL_OFFSET = Some(unix_us.saturating_sub(cur_time())); }
+}
+
+/// cur_time() as Unix time in us, if the wall clock has been aligned
+pub fn wall_time() -> Option<u64> {
+    unsafe { WALL_OFFSET }.map(|offset| offset + cur_time())
+}
+
+/// Token bucket rate limiter driven by cur_time()
+#[derive(Clone, Copy)]
+pub struct TokenBucket {
+    tokens: u32,
+    burst: u32,
+    interval: u64,
+    last: u64,
+}
+
+impl TokenBucket {
+    /// Allows bursts of `burst` events, refilling one token every `interval` us
+    pub const fn new(burst: u32, interval: u64) -> Self {
+        Self {
+            tokens: burst,
+            burst,
+            interval,
+            last: 0,
+        }
+    }
+
+    pub fn take(&mut self, now: u64) -> bool {
+        let refill = now.saturating_sub(self.last) / self.interval;
+        if refill > 0 {
+            self.tokens = core::cmp::min(self.burst as u64, self.tokens as u64 + refill) as u32;
+            self.last += refill * self.interval;
+        }
+
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+        true
+    }
+}